@@ -0,0 +1,362 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+
+/// First bytes of every capture file.
+const MAGIC: &[u8; 4] = b"SKCP";
+/// Format written by `CaptureWriter`. Readers take any version up to this one.
+const VERSION: u16 = 1;
+/// Bytes before the frame in a record: timestamp, connection and direction.
+const RECORD_HEADER_SIZE: usize = 8 + 4 + 1;
+/// Largest record read back, well above any frame a codec accepts.
+const MAX_RECORD_SIZE: usize = 16 * 1024 * 1024;
+/// Records waiting to be written before `CaptureWriter::record` waits for the file to catch up.
+const QUEUE_SIZE: usize = 1_024;
+
+/// Which way a captured frame went through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the connecting peer, usually the acquirer, to the upstream host.
+    FromClient,
+    /// From the upstream host back to the connecting peer.
+    FromUpstream,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::FromClient => 0,
+            Direction::FromUpstream => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, io::Error> {
+        match byte {
+            0 => Ok(Direction::FromClient),
+            1 => Ok(Direction::FromUpstream),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown direction {}", byte),
+            )),
+        }
+    }
+}
+
+/// One frame as it crossed the wire, length prefix included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub connection: u32,
+    pub direction: Direction,
+    pub frame: Vec<u8>,
+}
+
+impl Record {
+    pub fn now(connection: u32, direction: Direction, frame: Vec<u8>) -> Self {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_micros() as u64);
+
+        Self {
+            timestamp_us,
+            connection,
+            direction,
+            frame,
+        }
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), io::Error> {
+        writer.write_u32::<BigEndian>((RECORD_HEADER_SIZE + self.frame.len()) as u32)?;
+        writer.write_u64::<BigEndian>(self.timestamp_us)?;
+        writer.write_u32::<BigEndian>(self.connection)?;
+        writer.write_u8(self.direction.to_byte())?;
+        writer.write_all(&self.frame)
+    }
+
+    /// The next record, or `None` at the end of the capture. A record cut short, as the last
+    /// one is when the proxy was stopped while writing it, ends the capture too.
+    fn read(reader: &mut impl Read) -> Result<Option<Self>, io::Error> {
+        let mut length = [0; 4];
+        let read = read_fully(reader, &mut length)?;
+
+        if read < length.len() {
+            if read > 0 {
+                warn!("Capture ends partway through a record length");
+            }
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes(length) as usize;
+
+        if !(RECORD_HEADER_SIZE..=MAX_RECORD_SIZE).contains(&length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {} bytes is not a valid size", length),
+            ));
+        }
+
+        let mut record = vec![0; length];
+
+        if read_fully(reader, &mut record)? < length {
+            warn!("Capture ends partway through a record, ignoring it");
+            return Ok(None);
+        }
+
+        let mut fields = &record[..];
+        let timestamp_us = fields.read_u64::<BigEndian>()?;
+        let connection = fields.read_u32::<BigEndian>()?;
+        let direction = Direction::from_byte(fields.read_u8()?)?;
+        let frame = fields.to_vec();
+
+        Ok(Some(Self {
+            timestamp_us,
+            connection,
+            direction,
+            frame,
+        }))
+    }
+}
+
+/// Appends records to a capture file.
+///
+/// The file starts with the magic bytes `SKCP` and a big-endian 2-byte format version. Each
+/// record after that is a big-endian 4-byte length, then that many bytes: the timestamp
+/// (8 bytes), connection ID (4 bytes) and direction (1 byte), with the frame taking the rest.
+///
+/// Records are written by a task of its own, so relaying never waits on the disk unless the
+/// queue fills up. The task stops once the writer is dropped and the queue is drained, or on
+/// the first write error.
+#[derive(Debug)]
+pub struct CaptureWriter {
+    queue: mpsc::Sender<Queued>,
+}
+
+#[derive(Debug)]
+enum Queued {
+    Record(Record),
+    /// Answered once every record queued before has been written.
+    Flush(oneshot::Sender<()>),
+}
+
+impl CaptureWriter {
+    /// Creates the file at `path` and starts the task that writes to it.
+    pub async fn create(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let mut header = Vec::new();
+        write_header(&mut header)?;
+
+        let mut file = fs::File::create(path).await?;
+        file.write_all(&header).await?;
+        file.flush().await?;
+
+        let (queue, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = write_records(file, receiver).await {
+                warn!("Capture stopped, unable to write to it: {}", e);
+            }
+        });
+
+        Ok(Self { queue })
+    }
+
+    /// Queues `record` to be written. Fails once the writing task has stopped.
+    pub async fn record(&self, record: Record) -> Result<(), io::Error> {
+        self.queue
+            .send(Queued::Record(record))
+            .await
+            .map_err(|_| stopped())
+    }
+
+    /// Waits until every record queued before is written through to the file.
+    pub async fn flush(&self) -> Result<(), io::Error> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.queue
+            .send(Queued::Flush(sender))
+            .await
+            .map_err(|_| stopped())?;
+        receiver.await.map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "capture is no longer being written",
+    )
+}
+
+/// Writes whatever is queued in one go, flushing after each batch so a capture cut short still
+/// holds every frame recorded before.
+async fn write_records(
+    mut file: fs::File,
+    mut queue: mpsc::Receiver<Queued>,
+) -> Result<(), io::Error> {
+    let mut buffer = Vec::new();
+    let mut flushes = Vec::new();
+
+    while let Some(queued) = queue.recv().await {
+        let mut next = Some(queued);
+
+        while let Some(queued) = next.take() {
+            match queued {
+                Queued::Record(record) => record.write(&mut buffer)?,
+                Queued::Flush(flushed) => flushes.push(flushed),
+            }
+            next = queue.try_recv().ok();
+        }
+
+        file.write_all(&buffer).await?;
+        file.flush().await?;
+        buffer.clear();
+
+        for flushed in flushes.drain(..) {
+            let _ = flushed.send(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Every record in the capture at `path`, in the order they were recorded.
+pub fn read_capture(path: impl AsRef<Path>) -> Result<Vec<Record>, io::Error> {
+    read_records(&mut BufReader::new(File::open(path)?))
+}
+
+/// Fills as much of `buffer` as `reader` has left, returning how much that was.
+fn read_fully(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
+fn write_header(writer: &mut impl Write) -> Result<(), io::Error> {
+    writer.write_all(MAGIC)?;
+    writer.write_u16::<BigEndian>(VERSION)
+}
+
+fn read_records(reader: &mut impl Read) -> Result<Vec<Record>, io::Error> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a socketron capture",
+        ));
+    }
+
+    let version = reader.read_u16::<BigEndian>()?;
+
+    if version == 0 || version > VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported capture version {}", version),
+        ));
+    }
+
+    let mut records = Vec::new();
+
+    while let Some(record) = Record::read(reader)? {
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use super::{read_capture, read_records, write_header, CaptureWriter, Direction, Record};
+
+    #[test]
+    fn should_read_back_written_records() {
+        let records = vec![
+            Record::now(1, Direction::FromClient, b"\x00\x040100".to_vec()),
+            Record::now(1, Direction::FromUpstream, b"\x00\x040110".to_vec()),
+        ];
+        let mut buffer = Vec::new();
+        write_header(&mut buffer).unwrap();
+
+        for record in &records {
+            record.write(&mut buffer).unwrap();
+        }
+
+        assert_eq!(read_records(&mut &buffer[..]).unwrap(), records);
+    }
+
+    #[test]
+    fn should_end_capture_at_record_cut_short() {
+        let records = vec![
+            Record::now(1, Direction::FromClient, b"\x00\x040100".to_vec()),
+            Record::now(1, Direction::FromUpstream, b"\x00\x040110".to_vec()),
+        ];
+        let mut buffer = Vec::new();
+        write_header(&mut buffer).unwrap();
+
+        for record in &records {
+            record.write(&mut buffer).unwrap();
+        }
+        buffer.truncate(buffer.len() - 3);
+
+        assert_eq!(read_records(&mut &buffer[..]).unwrap(), records[..1]);
+    }
+
+    #[test]
+    fn should_reject_oversized_records() {
+        let mut buffer = Vec::new();
+        write_header(&mut buffer).unwrap();
+        buffer.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        assert!(read_records(&mut &buffer[..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_write_queued_records_before_flush_returns() {
+        let path = env::temp_dir().join(format!("socketron-capture-{}.cap", std::process::id()));
+        let records = vec![
+            Record::now(1, Direction::FromClient, b"\x00\x040100".to_vec()),
+            Record::now(1, Direction::FromUpstream, b"\x00\x040110".to_vec()),
+        ];
+
+        let capture = CaptureWriter::create(&path).await.unwrap();
+        for record in &records {
+            capture.record(record.clone()).await.unwrap();
+        }
+        capture.flush().await.unwrap();
+        let written = read_capture(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(written, records);
+    }
+
+    #[test]
+    fn should_reject_unknown_versions() {
+        let mut buffer = b"SKCP".to_vec();
+        buffer.extend_from_slice(&2u16.to_be_bytes());
+
+        assert!(read_records(&mut &buffer[..]).is_err());
+        assert!(read_records(&mut &b"\x00\x040100"[..]).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Parser, Subcommand};
+use socketron::{
+    config::{ConfigError, LogFormat, LogLevel},
+    length_prefix::LengthEncoding,
+    message_header::HeaderFormat,
+    Config,
+};
+
+/// ISO 8583 host simulator.
+///
+/// Settings come from the defaults, then the config file, then environment variables, then
+/// flags, each overriding the one before. Without a command it runs the server.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML config file
+    #[arg(long, global = true, env = "SOCKETRON_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, can be repeated
+    #[arg(long, global = true, env = "SOCKETRON_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+
+    /// Port for every listen address
+    #[arg(long, global = true, env = "SOCKETRON_PORT")]
+    pub port: Option<u16>,
+
+    /// Bytes in the length prefix
+    #[arg(long, global = true, env = "SOCKETRON_LENGTH_PREFIX_WIDTH")]
+    pub length_prefix_width: Option<usize>,
+
+    /// binary-big-endian, binary-little-endian, ascii, bcd or ebcdic
+    #[arg(long, global = true, env = "SOCKETRON_LENGTH_ENCODING")]
+    pub length_encoding: Option<LengthEncoding>,
+
+    /// The length prefix counts its own bytes
+    #[arg(long, global = true, env = "SOCKETRON_LENGTH_INCLUSIVE")]
+    pub length_inclusive: Option<bool>,
+
+    /// tpdu, visa or routing:LENGTH:DESTINATION:SOURCE:ADDRESS_LENGTH header after the
+    /// length prefix
+    #[arg(long, global = true, env = "SOCKETRON_HEADER_FORMAT")]
+    pub header_format: Option<HeaderFormat>,
+
+    /// Largest frame accepted before the connection is dropped
+    #[arg(long, global = true, env = "SOCKETRON_MAX_FRAME_SIZE")]
+    pub max_frame_size: Option<usize>,
+
+    /// Reject financial requests until the peer signs on
+    #[arg(long, global = true, env = "SOCKETRON_REQUIRE_SIGN_ON")]
+    pub require_sign_on: Option<bool>,
+
+    /// Milliseconds a connection may be quiet before an echo test is sent, 0 to turn off
+    #[arg(long, global = true, env = "SOCKETRON_HEARTBEAT_IDLE_MS")]
+    pub heartbeat_idle_ms: Option<u64>,
+
+    /// Unanswered echo tests in a row before the connection is closed
+    #[arg(long, global = true, env = "SOCKETRON_HEARTBEAT_MAX_MISSED")]
+    pub heartbeat_max_missed: Option<u32>,
+
+    /// Field 39 of every response
+    #[arg(long, global = true, env = "SOCKETRON_RESPONSE_CODE")]
+    pub response_code: Option<String>,
+
+    /// Milliseconds to wait before responding
+    #[arg(long, global = true, env = "SOCKETRON_DELAY_MS")]
+    pub delay_ms: Option<u64>,
+
+    /// Milliseconds a retransmitted request gets the first copy's response, 0 to turn off
+    #[arg(long, global = true, env = "SOCKETRON_DUPLICATE_WINDOW_MS")]
+    pub duplicate_window_ms: Option<u64>,
+
+    /// Seed for response latencies, to repeat a run
+    #[arg(long, global = true, env = "SOCKETRON_SEED")]
+    pub seed: Option<u64>,
+
+    /// TOML file of response rules
+    #[arg(long, global = true, env = "SOCKETRON_RULES")]
+    pub rules: Option<PathBuf>,
+
+    /// JSON or CSV file of card accounts to check balances against
+    #[arg(long, global = true, env = "SOCKETRON_ACCOUNTS")]
+    pub accounts: Option<PathBuf>,
+
+    /// error, warn, info, debug or trace
+    #[arg(long, global = true, env = "SOCKETRON_LOG_LEVEL")]
+    pub log_level: Option<LogLevel>,
+
+    /// pretty or json
+    #[arg(long, global = true, env = "SOCKETRON_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Print the effective settings as TOML and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Connect to a host as an acquirer and send it messages, printing each response
+    Client(ClientArgs),
+    /// Relay connections on the listen addresses to an upstream host, recording the frames
+    Proxy(ProxyArgs),
+    /// Play the requests in a capture against a host, reporting responses that differ
+    Replay(ReplayArgs),
+    /// Turn a JSON message of field numbers to values into a length-prefixed frame
+    Encode(ConvertArgs),
+    /// Turn a length-prefixed frame into a JSON message of field numbers to values
+    Decode(ConvertArgs),
+    /// Print messages field by field with the field names, masking card secrets
+    Inspect(InspectArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ClientArgs {
+    /// Host to connect to, as host:port
+    pub connect: String,
+
+    /// .bin or .json messages to send, in order
+    #[arg(required = true)]
+    pub messages: Vec<PathBuf>,
+
+    /// Milliseconds to wait for each response
+    #[arg(long, default_value_t = 30_000)]
+    pub timeout_ms: u64,
+
+    /// Give each message a new STAN and transmission datetime before sending it
+    #[arg(long)]
+    pub stamp: bool,
+
+    /// Do not reverse 0100 and 0200 requests that time out
+    #[arg(long)]
+    pub no_auto_reversal: bool,
+
+    /// Queue advices in this log and send them until answered, resuming what an earlier run
+    /// left undelivered
+    #[arg(long)]
+    pub store_and_forward: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ProxyArgs {
+    /// Host to relay connections to, as host:port
+    #[arg(long)]
+    pub upstream: String,
+
+    /// File to record every relayed frame in
+    #[arg(long)]
+    pub capture: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ReplayArgs {
+    /// Host to play the capture against, as host:port
+    pub target: String,
+
+    /// Capture recorded by the proxy
+    pub capture: PathBuf,
+
+    /// Times faster than recorded to send the requests
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f64,
+
+    /// Milliseconds to wait for each response
+    #[arg(long, default_value_t = 30_000)]
+    pub timeout_ms: u64,
+
+    /// Fields to leave out when comparing responses
+    #[arg(long, value_delimiter = ',')]
+    pub ignore_fields: Vec<usize>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ConvertArgs {
+    /// Message to convert
+    pub input: PathBuf,
+
+    /// File to write, by default the input with its extension changed to .bin or .json
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct InspectArgs {
+    /// .bin or .json messages to print
+    #[arg(required = true)]
+    pub messages: Vec<PathBuf>,
+
+    /// Print the PAN, track data and PIN block as they are
+    #[arg(long)]
+    pub unmasked: bool,
+}
+
+impl Args {
+    /// Loads the config file, if any, and applies the overrides on top of it.
+    pub fn config(&self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen.clone();
+        }
+        if let Some(port) = self.port {
+            for addr in &mut config.listen {
+                addr.set_port(port);
+            }
+        }
+        if let Some(length_prefix_width) = self.length_prefix_width {
+            config.framing.length_prefix_width = length_prefix_width;
+        }
+        if let Some(length_encoding) = self.length_encoding {
+            config.framing.length_encoding = length_encoding;
+        }
+        if let Some(length_inclusive) = self.length_inclusive {
+            config.framing.length_inclusive = length_inclusive;
+        }
+        if let Some(header_format) = self.header_format {
+            config.framing.header = Some(header_format);
+        }
+        if let Some(max_frame_size) = self.max_frame_size {
+            config.framing.max_frame_size = max_frame_size;
+        }
+        if let Some(require_sign_on) = self.require_sign_on {
+            config.network.require_sign_on = require_sign_on;
+        }
+        if let Some(heartbeat_idle_ms) = self.heartbeat_idle_ms {
+            config.network.heartbeat_idle_ms = heartbeat_idle_ms;
+        }
+        if let Some(heartbeat_max_missed) = self.heartbeat_max_missed {
+            config.network.heartbeat_max_missed = heartbeat_max_missed;
+        }
+        if let Some(response_code) = &self.response_code {
+            config.responses.response_code = response_code.clone();
+        }
+        if let Some(delay_ms) = self.delay_ms {
+            config.responses.delay_ms = delay_ms;
+        }
+        if let Some(duplicate_window_ms) = self.duplicate_window_ms {
+            config.responses.duplicate_window_ms = duplicate_window_ms;
+        }
+        if let Some(seed) = self.seed {
+            config.responses.seed = Some(seed);
+        }
+        if let Some(rules) = &self.rules {
+            config.responses.rules_file = Some(rules.clone());
+        }
+        if let Some(accounts) = &self.accounts {
+            config.responses.accounts_file = Some(accounts.clone());
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use socketron::length_prefix::LengthEncoding;
+
+    use super::{Args, Command};
+
+    #[test]
+    fn should_override_defaults_with_flags() {
+        let args = Args::try_parse_from([
+            "socketron",
+            "--listen",
+            "0.0.0.0:9000",
+            "--length-encoding",
+            "ascii",
+            "--length-prefix-width",
+            "4",
+            "--delay-ms",
+            "0",
+        ])
+        .unwrap();
+
+        let config = args.config().unwrap();
+
+        assert_eq!(config.listen, ["0.0.0.0:9000".parse().unwrap()]);
+        assert_eq!(config.framing.length_encoding, LengthEncoding::Ascii);
+        assert_eq!(config.framing.length_prefix_width, 4);
+        assert_eq!(config.responses.delay_ms, 0);
+    }
+
+    #[test]
+    fn should_set_port_on_every_listen_address() {
+        let args = Args::try_parse_from([
+            "socketron",
+            "--listen",
+            "127.0.0.1:1,0.0.0.0:2",
+            "--port",
+            "9000",
+        ])
+        .unwrap();
+
+        let config = args.config().unwrap();
+
+        assert!(config.listen.iter().all(|addr| addr.port() == 9000));
+    }
+
+    #[test]
+    fn should_reject_unknown_length_encoding() {
+        assert!(Args::try_parse_from(["socketron", "--length-encoding", "hex"]).is_err());
+    }
+
+    #[test]
+    fn should_accept_framing_flags_after_client_command() {
+        let args = Args::try_parse_from([
+            "socketron",
+            "client",
+            "127.0.0.1:9000",
+            "sample_messages/financial-advice.json",
+            "--length-encoding",
+            "ascii",
+        ])
+        .unwrap();
+
+        assert!(matches!(args.command, Some(Command::Client(_))));
+        assert_eq!(
+            args.config().unwrap().framing.length_encoding,
+            LengthEncoding::Ascii
+        );
+    }
+
+    #[test]
+    fn should_accept_listen_flags_after_proxy_command() {
+        let args = Args::try_parse_from([
+            "socketron",
+            "proxy",
+            "--upstream",
+            "127.0.0.1:9000",
+            "--listen",
+            "127.0.0.1:8583",
+            "--require-sign-on",
+            "true",
+            "--print-config",
+        ])
+        .unwrap();
+
+        let config = args.config().unwrap();
+
+        assert!(matches!(args.command, Some(Command::Proxy(_))));
+        assert!(args.print_config);
+        assert_eq!(config.listen, ["127.0.0.1:8583".parse().unwrap()]);
+        assert!(config.network.require_sign_on);
+    }
+}
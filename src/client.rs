@@ -0,0 +1,56 @@
+use std::{fs, io, path::Path};
+
+use bytes::BytesMut;
+use iso_8583_message::IsoMessage;
+use tokio_util::codec::Decoder;
+
+use crate::{fields::from_json, message_machine::IsoFrameCodec};
+
+/// Reads a message from a `.json` object of field numbers to values, like
+/// `sample_messages/financial-advice.json`, or from any other file holding exactly one frame
+/// as `codec` frames it, like the `.bin` files in `sample_messages` with the default codec.
+pub fn read_message(
+    path: impl AsRef<Path>,
+    codec: &IsoFrameCodec,
+) -> Result<IsoMessage, io::Error> {
+    let path = path.as_ref();
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), reason),
+        )
+    };
+
+    if path.extension().and_then(|extension| extension.to_str()) == Some("json") {
+        return from_json(&fs::read_to_string(path)?).map_err(invalid);
+    }
+
+    let mut buffer = BytesMut::from(&fs::read(path)?[..]);
+
+    match codec.clone().decode(&mut buffer) {
+        Ok(Some(_)) if !buffer.is_empty() => {
+            Err(invalid(format!("{} bytes after the frame", buffer.len())))
+        }
+        Ok(Some(frame)) => Ok(frame.message),
+        Ok(None) => Err(invalid("incomplete frame".to_string())),
+        Err(error) => Err(invalid(error.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::read_message;
+    use crate::message_machine::IsoFrameCodec;
+
+    #[test]
+    fn should_read_json_and_bin_messages() {
+        let codec = IsoFrameCodec::default();
+        let advice = read_message("sample_messages/financial-advice.json", &codec).unwrap();
+        let request =
+            read_message("sample_messages/i2c-authorization-request.bin", &codec).unwrap();
+
+        assert_eq!(advice.get_field(0), Some("0220"));
+        assert_eq!(advice.get_field(37), Some("623456123483"));
+        assert_eq!(request.get_field(0), Some("0100"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::{error::Error, time::Duration};
+
+use chrono::Utc;
+use iso_8583_message::IsoMessage;
+use socketron::{
+    client::read_message,
+    correlator::Correlator,
+    reversal::{send_or_reverse, Exchange},
+    store_forward::StoreAndForward,
+    Config,
+};
+use tokio::time::Instant;
+
+use crate::cli::ClientArgs;
+
+/// Sends every message in `args` over one connection and prints the responses.
+pub async fn run(config: &Config, args: &ClientArgs) -> Result<(), Box<dyn Error>> {
+    let correlator = Correlator::connect(&args.connect, config.framing.codec())
+        .await?
+        .timeout(Duration::from_millis(args.timeout_ms));
+    // Start somewhere different on every run so stamped requests are not taken for
+    // retransmissions of the last run's
+    let first_stan = Utc::now().timestamp() as u32 % 999_999;
+    let mut policy = config.reversals.clone();
+    policy.auto &= !args.no_auto_reversal;
+    let store = match &args.store_and_forward {
+        Some(path) => Some(StoreAndForward::open(path)?),
+        None => None,
+    };
+
+    let codec = config.framing.codec();
+
+    for (index, path) in args.messages.iter().enumerate() {
+        let mut request = read_message(path, &codec)?;
+
+        if args.stamp {
+            let stan = (first_stan + index as u32) % 999_999 + 1;
+            request.set_field(7, &Utc::now().format("%m%d%H%M%S").to_string())?;
+            request.set_field(11, &format!("{:06}", stan))?;
+        }
+
+        if let Some(store) = &store {
+            if is_advice(&request) {
+                store.enqueue(request)?;
+                println!(
+                    "{}: queued, {} advice(s) waiting",
+                    path.display(),
+                    store.depth()
+                );
+                continue;
+            }
+        }
+
+        let sent = Instant::now();
+
+        match send_or_reverse(&correlator, request.clone(), &policy).await? {
+            Exchange::Answered(response) => println!(
+                "{}: {} -> {} in {} ms",
+                path.display(),
+                request.get_field(0).unwrap_or("????"),
+                describe(&response),
+                sent.elapsed().as_millis()
+            ),
+            Exchange::Reversed(reversal) => println!(
+                "{}: {} timed out, reversal -> {} after {} ms",
+                path.display(),
+                request.get_field(0).unwrap_or("????"),
+                match &reversal {
+                    Ok(response) => describe(response),
+                    Err(e) => e.to_string(),
+                },
+                sent.elapsed().as_millis()
+            ),
+        }
+    }
+
+    if let Some(store) = &store {
+        let sent = Instant::now();
+        let waiting = store.depth();
+        store.forward(&correlator, &policy).await?;
+
+        println!(
+            "{}: delivered {} advice(s) in {} ms",
+            store.path().display(),
+            waiting,
+            sent.elapsed().as_millis()
+        );
+    }
+
+    Ok(())
+}
+
+fn is_advice(message: &IsoMessage) -> bool {
+    matches!(message.get_field(0), Some("0120" | "0220" | "0420"))
+}
+
+fn describe(response: &IsoMessage) -> String {
+    format!(
+        "{} response code {}",
+        response.get_field(0).unwrap_or("????"),
+        response.get_field(39).unwrap_or("--")
+    )
+}
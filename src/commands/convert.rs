@@ -0,0 +1,48 @@
+use std::{error::Error, fs, path::PathBuf};
+
+use bytes::BytesMut;
+use socketron::{
+    client::read_message,
+    fields::{from_json, to_json},
+    Config, IsoFrame,
+};
+use tokio_util::codec::Encoder;
+
+use crate::cli::ConvertArgs;
+
+/// Writes the JSON message in `args` as one frame, framed as configured.
+pub fn encode(config: &Config, args: &ConvertArgs) -> Result<(), Box<dyn Error>> {
+    let message = from_json(&fs::read_to_string(&args.input)?)
+        .map_err(|reason| format!("{}: {}", args.input.display(), reason))?;
+    let mut frame = BytesMut::new();
+    config
+        .framing
+        .codec()
+        .encode(IsoFrame::from(message), &mut frame)?;
+
+    let output = output(args, "bin");
+    fs::write(&output, &frame)?;
+    println!("{}: {} bytes", output.display(), frame.len());
+
+    Ok(())
+}
+
+/// Writes the frame in `args`, framed as configured, as a JSON message.
+pub fn decode(config: &Config, args: &ConvertArgs) -> Result<(), Box<dyn Error>> {
+    let message = read_message(&args.input, &config.framing.codec())?;
+    let output = output(args, "json");
+    fs::write(&output, to_json(&message) + "\n")?;
+    println!(
+        "{}: {}",
+        output.display(),
+        message.get_field(0).unwrap_or("????")
+    );
+
+    Ok(())
+}
+
+fn output(args: &ConvertArgs, extension: &str) -> PathBuf {
+    args.output
+        .clone()
+        .unwrap_or_else(|| args.input.with_extension(extension))
+}
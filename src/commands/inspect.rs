@@ -0,0 +1,30 @@
+use std::error::Error;
+
+use socketron::{client::read_message, pretty::pretty, Config};
+
+use crate::cli::InspectArgs;
+
+/// Prints every message in `args`, each under its file name.
+pub fn run(config: &Config, args: &InspectArgs) -> Result<(), Box<dyn Error>> {
+    let codec = config.framing.codec();
+
+    for (index, path) in args.messages.iter().enumerate() {
+        let message = read_message(path, &codec)?;
+        let pretty = pretty(&message);
+
+        if index > 0 {
+            println!();
+        }
+        println!("{}", path.display());
+        println!(
+            "{}",
+            if args.unmasked {
+                pretty.unmasked()
+            } else {
+                pretty
+            }
+        );
+    }
+
+    Ok(())
+}
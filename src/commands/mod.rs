@@ -0,0 +1,5 @@
+pub mod client;
+pub mod convert;
+pub mod inspect;
+pub mod proxy;
+pub mod replay;
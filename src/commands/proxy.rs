@@ -0,0 +1,34 @@
+use std::{error::Error, sync::Arc};
+
+use futures::future::try_join_all;
+use socketron::{capture::CaptureWriter, proxy::Proxy, Config};
+use tracing::info;
+
+use crate::cli::ProxyArgs;
+
+/// Relays every listen address to the upstream host, sharing one capture between them.
+pub async fn run(config: &Config, args: &ProxyArgs) -> Result<(), Box<dyn Error>> {
+    let capture = match &args.capture {
+        Some(path) => Some(Arc::new(CaptureWriter::create(path).await?)),
+        None => None,
+    };
+    let mut proxies = Vec::new();
+
+    for addr in &config.listen {
+        let mut proxy = Proxy::bind(addr, &args.upstream, config.framing.codec()).await?;
+        info!(
+            "Proxy started up on {}, relaying to {}",
+            proxy.local_addr()?,
+            args.upstream
+        );
+
+        if let Some(capture) = &capture {
+            proxy = proxy.capture(capture.clone());
+        }
+        proxies.push(proxy.run());
+    }
+
+    try_join_all(proxies).await?;
+
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::{error::Error, time::Duration};
+
+use iso_8583_message::IsoMessage;
+use socketron::{capture::read_capture, replay::Replay, Config};
+
+use crate::cli::ReplayArgs;
+
+/// Plays the capture in `args` against its target and prints how each response compares
+/// with the recorded one. Fails if any of them differ.
+pub async fn run(config: &Config, args: &ReplayArgs) -> Result<(), Box<dyn Error>> {
+    let records = read_capture(&args.capture)?;
+    let replayed = Replay::new(&args.target, config.framing.codec())
+        .speed(args.speed)
+        .timeout(Duration::from_millis(args.timeout_ms))
+        .ignore_fields(&args.ignore_fields)
+        .run(&records)
+        .await?;
+    let mut differed = 0;
+
+    for replayed in &replayed {
+        let request = mti(&replayed.request);
+        let response = match &replayed.response {
+            Ok(response) => response,
+            Err(e) => {
+                differed += 1;
+                println!("connection {}: {} -> {}", replayed.connection, request, e);
+                continue;
+            }
+        };
+        let recorded = match &replayed.recorded {
+            Some(recorded) => recorded,
+            None => {
+                println!(
+                    "connection {}: {} -> {}, none recorded",
+                    replayed.connection,
+                    request,
+                    mti(response)
+                );
+                continue;
+            }
+        };
+
+        if replayed.matched() {
+            println!(
+                "connection {}: {} -> {} as recorded",
+                replayed.connection,
+                request,
+                mti(response)
+            );
+            continue;
+        }
+
+        differed += 1;
+        println!(
+            "connection {}: {} -> {} differs from the recording",
+            replayed.connection,
+            request,
+            mti(response)
+        );
+
+        for field in &replayed.differences {
+            println!(
+                "    field {}: recorded {:?}, replayed {:?}",
+                field,
+                recorded.get_field(*field),
+                response.get_field(*field)
+            );
+        }
+    }
+
+    println!(
+        "{}: {} request(s) replayed, {} differed",
+        args.capture.display(),
+        replayed.len(),
+        differed
+    );
+
+    if differed > 0 {
+        return Err(format!("{} response(s) differ from the capture", differed).into());
+    }
+
+    Ok(())
+}
+
+fn mti(message: &IsoMessage) -> &str {
+    message.get_field(0).unwrap_or("????")
+}
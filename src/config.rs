@@ -0,0 +1,590 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    duplicates::Duplicates,
+    journal::Journal,
+    latency::{Latency, LatencyProfiles},
+    ledger::{Account, Ledger},
+    length_prefix::{LengthEncoding, LengthPrefix},
+    message_handler::{Approve, MessageHandler, Router},
+    message_header::HeaderFormat,
+    message_machine::IsoFrameCodec,
+    network::Heartbeat,
+    reversal::RetryPolicy,
+    rules::{RuleEngine, Rules},
+    server::ServerBuilder,
+    MAX_MESSAGE_SIZE,
+};
+
+/// Largest `max_frame_size` allowed, however wide the length prefix. Each connection may
+/// buffer a frame this size.
+const MAX_FRAME_SIZE_LIMIT: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    ParseJson(serde_json::Error),
+    /// The file parsed but a setting is out of range.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "unable to read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "unable to parse config: {}", e),
+            ConfigError::ParseJson(e) => write!(f, "unable to parse config: {}", e),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::ParseJson(e) => Some(e),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+/// Settings for the `socketron` binary, read from a TOML file. Every key is optional.
+///
+/// ```toml
+/// listen = ["127.0.0.1:8006"]
+/// log_level = "info"
+/// log_format = "pretty"
+///
+/// [framing]
+/// length_prefix_width = 2
+/// length_encoding = "binary-big-endian"
+/// length_inclusive = false
+/// max_frame_size = 3418
+///
+/// [framing.header]
+/// kind = "tpdu"
+///
+/// [network]
+/// require_sign_on = false
+/// heartbeat_idle_ms = 30000
+/// heartbeat_max_missed = 3
+///
+/// [responses]
+/// response_code = "00"
+/// delay_ms = 2000
+/// rules_file = "rules.toml"
+/// accounts_file = "accounts.json"
+/// seed = 42
+/// match_originals = true
+/// duplicate_window_ms = 60000
+///
+/// [responses.latency."0100"]
+/// kind = "normal"
+/// mean_ms = 300
+/// std_dev_ms = 80
+///
+/// [reversals]
+/// auto = true
+/// max_attempts = 5
+/// retry_interval_ms = 1000
+/// backoff = 2.0
+/// max_interval_ms = 60000
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub log_level: LogLevel,
+    /// `pretty` for one readable line per event, `json` for one JSON object per event. Either
+    /// way events carry the fields of the connection and message spans they happened in.
+    pub log_format: LogFormat,
+    pub framing: FramingConfig,
+    pub network: NetworkConfig,
+    pub responses: ResponseConfig,
+    /// How `socketron client` reverses requests that time out.
+    pub reversals: RetryPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FramingConfig {
+    pub length_prefix_width: usize,
+    pub length_encoding: LengthEncoding,
+    /// Whether the length prefix counts its own bytes.
+    pub length_inclusive: bool,
+    pub max_frame_size: usize,
+    /// Header between the length prefix and the MTI, if the link has one. See `HeaderFormat`.
+    pub header: Option<HeaderFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Reject authorization, financial and reversal requests with response code 91 until the
+    /// peer signs on with an 0800.
+    pub require_sign_on: bool,
+    /// Milliseconds without traffic from the peer before sending an 0800 echo test. Zero
+    /// turns heartbeats off.
+    pub heartbeat_idle_ms: u64,
+    /// Echo tests in a row the peer may leave unanswered before the connection is closed.
+    pub heartbeat_max_missed: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseConfig {
+    /// Field 39 of responses that no rule matched.
+    pub response_code: String,
+    /// Delay for MTIs without a latency profile.
+    pub delay_ms: u64,
+    /// Rules tried before falling back to `response_code`, see `Rules`.
+    pub rules_file: Option<PathBuf>,
+    /// JSON or CSV accounts for a `Ledger` that answers requests no rule matched.
+    pub accounts_file: Option<PathBuf>,
+    /// Seeds the latency RNG so runs can be repeated.
+    pub seed: Option<u64>,
+    /// Match 0220 and 0420 messages to the requests they refer to, answering 25 when there
+    /// is none, see `Journal`.
+    pub match_originals: bool,
+    /// How long a 0100 or 0200 is remembered so a retransmission gets the same response,
+    /// see `Duplicates`. Zero turns duplicate detection off.
+    pub duplicate_window_ms: u64,
+    /// Latency profiles by MTI.
+    pub latency: BTreeMap<String, Latency>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+
+        contents.parse()
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config always serializes")
+    }
+
+    /// Checks the settings that would otherwise only fail once the server is running.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::Invalid(
+                "at least one listen address is required".to_string(),
+            ));
+        }
+        if self.framing.length_prefix_width == 0 {
+            return Err(ConfigError::Invalid(
+                "length_prefix_width must be at least 1".to_string(),
+            ));
+        }
+        if !(1..=MAX_FRAME_SIZE_LIMIT).contains(&self.framing.max_frame_size) {
+            return Err(ConfigError::Invalid(format!(
+                "max_frame_size must be between 1 and {}, got {}",
+                MAX_FRAME_SIZE_LIMIT, self.framing.max_frame_size
+            )));
+        }
+        if let Some(header) = &self.framing.header {
+            header
+                .validate()
+                .map_err(|reason| ConfigError::Invalid(format!("framing.header: {}", reason)))?;
+        }
+        if self.network.heartbeat_max_missed == 0 {
+            return Err(ConfigError::Invalid(
+                "heartbeat_max_missed must be at least 1".to_string(),
+            ));
+        }
+        if self.responses.response_code.len() != 2 {
+            return Err(ConfigError::Invalid(format!(
+                "response_code must be 2 characters, got {:?}",
+                self.responses.response_code
+            )));
+        }
+        self.reversals
+            .validate()
+            .map_err(|reason| ConfigError::Invalid(format!("reversals: {}", reason)))?;
+        for (mti, latency) in &self.responses.latency {
+            latency.validate().map_err(|reason| {
+                ConfigError::Invalid(format!("latency for {}: {}", mti, reason))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// A `ServerBuilder` with the framing and responses from this config. Loads the rules
+    /// file, if there is one.
+    pub fn server_builder(&self) -> Result<ServerBuilder, ConfigError> {
+        self.validate()?;
+
+        let rules = match &self.responses.rules_file {
+            Some(path) => Rules::load(path)?,
+            None => Rules::default(),
+        };
+        let approve = Approve::new(Duration::ZERO).response_code(&self.responses.response_code);
+        let fallback: Box<dyn MessageHandler> = match &self.responses.accounts_file {
+            Some(path) => Box::new(Ledger::new(Account::load(path)?, approve)),
+            None => Box::new(approve),
+        };
+
+        let engine = RuleEngine::new(rules, self.responses.latency_profiles(), fallback);
+        let mut handler: Box<dyn MessageHandler> = if self.responses.match_originals {
+            Box::new(Journal::new(engine))
+        } else {
+            Box::new(engine)
+        };
+        if self.responses.duplicate_window_ms > 0 {
+            let window = Duration::from_millis(self.responses.duplicate_window_ms);
+            handler = Box::new(Duplicates::new(window, handler));
+        }
+
+        let mut builder = ServerBuilder::new()
+            .length_prefix(self.framing.length_prefix())
+            .max_message_size(self.framing.max_frame_size())
+            .require_sign_on(self.network.require_sign_on)
+            .handler(Router::new(handler));
+
+        if let Some(header) = self.framing.header {
+            builder = builder.header_format(header);
+        }
+
+        Ok(match self.network.heartbeat() {
+            Some(heartbeat) => builder.heartbeat(heartbeat),
+            None => builder,
+        })
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Config = toml::from_str(s).map_err(ConfigError::Parse)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 8006))],
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Pretty,
+            framing: FramingConfig::default(),
+            network: NetworkConfig::default(),
+            responses: ResponseConfig::default(),
+            reversals: RetryPolicy::default(),
+        }
+    }
+}
+
+impl FramingConfig {
+    /// A codec for this framing, for connections made outside a `Server`.
+    pub fn codec(&self) -> IsoFrameCodec {
+        IsoFrameCodec::new(self.length_prefix(), self.header)
+            .max_message_size(self.max_frame_size())
+    }
+
+    /// `max_frame_size`, or less if the length prefix cannot announce a frame that long.
+    pub fn max_frame_size(&self) -> usize {
+        let announceable = self.length_prefix().max_message_size();
+
+        self.max_frame_size
+            .min(usize::try_from(announceable).unwrap_or(usize::MAX))
+    }
+
+    pub fn length_prefix(&self) -> LengthPrefix {
+        let length_prefix = LengthPrefix::new(self.length_prefix_width, self.length_encoding);
+
+        if self.length_inclusive {
+            length_prefix.inclusive()
+        } else {
+            length_prefix
+        }
+    }
+}
+
+impl Default for FramingConfig {
+    fn default() -> Self {
+        Self {
+            length_prefix_width: 2,
+            length_encoding: LengthEncoding::BinaryBigEndian,
+            length_inclusive: false,
+            max_frame_size: MAX_MESSAGE_SIZE,
+            header: None,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn heartbeat(&self) -> Option<Heartbeat> {
+        if self.heartbeat_idle_ms == 0 {
+            return None;
+        }
+
+        Some(Heartbeat {
+            idle: Duration::from_millis(self.heartbeat_idle_ms),
+            max_missed: self.heartbeat_max_missed,
+        })
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            require_sign_on: false,
+            heartbeat_idle_ms: 0,
+            heartbeat_max_missed: 3,
+        }
+    }
+}
+
+impl ResponseConfig {
+    pub fn latency_profiles(&self) -> LatencyProfiles {
+        let latencies =
+            LatencyProfiles::new(Latency::Fixed { ms: self.delay_ms }, self.latency.clone());
+
+        match self.seed {
+            Some(seed) => latencies.seed(seed),
+            None => latencies,
+        }
+    }
+}
+
+impl Default for ResponseConfig {
+    fn default() -> Self {
+        Self {
+            response_code: "00".to_string(),
+            delay_ms: 2_000,
+            rules_file: None,
+            accounts_file: None,
+            seed: None,
+            match_originals: true,
+            duplicate_window_ms: 60_000,
+            latency: BTreeMap::new(),
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!(
+                "unknown log level {:?}, expected one of error, warn, info, debug or trace",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format {:?}, expected pretty or json",
+                s
+            )),
+        }
+    }
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, ConfigError, LogFormat, LogLevel};
+    use crate::latency::Latency;
+    use crate::length_prefix::{LengthEncoding, LengthPrefix};
+    use crate::message_header::HeaderFormat;
+
+    #[test]
+    fn should_use_defaults_for_missing_keys() {
+        let config: Config = "[responses]\nresponse_code = \"05\"\n".parse().unwrap();
+
+        assert_eq!(config.responses.response_code, "05");
+        assert_eq!(config.responses.delay_ms, 2_000);
+        assert_eq!(config.listen, Config::default().listen);
+        assert_eq!(config.framing.length_prefix(), LengthPrefix::default());
+    }
+
+    #[test]
+    fn should_parse_every_setting() {
+        let config: Config = r#"
+            listen = ["0.0.0.0:9000", "127.0.0.1:9001"]
+            log_level = "debug"
+            log_format = "json"
+
+            [framing]
+            length_prefix_width = 4
+            length_encoding = "ascii"
+            length_inclusive = true
+            max_frame_size = 8192
+
+            [framing.header]
+            kind = "routing"
+            length = 8
+            destination = 0
+            source = 4
+            address_length = 4
+
+            [responses]
+            response_code = "51"
+            delay_ms = 0
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(
+            config.framing.length_prefix(),
+            LengthPrefix::new(4, LengthEncoding::Ascii).inclusive()
+        );
+        assert_eq!(config.framing.max_frame_size, 8192);
+        assert_eq!(
+            config.framing.header,
+            Some(HeaderFormat::Routing {
+                length: 8,
+                destination: 0,
+                source: 4,
+                address_length: 4
+            })
+        );
+        assert_eq!(config.responses.delay_ms, 0);
+    }
+
+    #[test]
+    fn should_reject_routing_header_addresses_out_of_range() {
+        let results = "[framing.header]\nkind = \"routing\"\nlength = 4\n\
+                       destination = 0\nsource = 2\naddress_length = 4\n"
+            .parse::<Config>();
+
+        assert!(matches!(results, Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn should_reject_framing_the_codec_cannot_use() {
+        for framing in [
+            "length_prefix_width = 0",
+            "max_frame_size = 0",
+            "length_prefix_width = 8\nmax_frame_size = 1073741824",
+        ] {
+            let results = format!("[framing]\n{}\n", framing).parse::<Config>();
+
+            assert!(
+                matches!(results, Err(ConfigError::Invalid(_))),
+                "{}",
+                framing
+            );
+        }
+
+        let mut config = Config::default();
+        config.framing.length_encoding = LengthEncoding::Ascii;
+
+        assert_eq!(config.framing.max_frame_size(), 99);
+
+        config.framing.length_prefix_width = 0;
+
+        assert!(matches!(
+            config.server_builder(),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn should_reject_unknown_keys() {
+        let results = "[framing]\nlength_width = 4\n".parse::<Config>();
+
+        assert!(matches!(results, Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn should_round_trip_through_toml() {
+        let config = Config::default();
+
+        assert_eq!(config.to_toml().parse::<Config>().unwrap(), config);
+    }
+
+    #[test]
+    fn should_parse_latency_by_mti() {
+        let latency = r#"
+            [responses.latency."0100"]
+            kind = "uniform"
+            min_ms = 100
+            max_ms = 500
+        "#;
+        let config: Config = latency.parse().unwrap();
+
+        assert_eq!(
+            config.responses.latency["0100"],
+            Latency::Uniform {
+                min_ms: 100,
+                max_ms: 500
+            }
+        );
+        assert!(matches!(
+            latency.replace("500", "50").parse::<Config>(),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn should_reject_bad_response_code() {
+        let mut config = Config::default();
+        config.responses.response_code = "5".to_string();
+
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+    }
+}
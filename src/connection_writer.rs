@@ -0,0 +1,128 @@
+use tokio::{io::AsyncWrite, sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+use crate::message_machine::{FrameWriter, FramingError, IsoFrame};
+
+/// Most frames written by one vectored write.
+const MAX_BATCH_SIZE: usize = 64;
+
+/// Starts the task that owns the write half of a connection. Frames sent on the returned
+/// queue are written in order, batching whatever is waiting into one vectored write.
+///
+/// The task stops once every sender is dropped and the queue is drained, or on the first
+/// write error, which drops the queue so senders see it closed.
+pub fn spawn<W>(
+    frame_writer: FrameWriter<W>,
+    queue_size: usize,
+) -> (mpsc::Sender<IsoFrame>, JoinHandle<Result<(), FramingError>>)
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(queue_size);
+    let task = tokio::spawn(write_frames(frame_writer, receiver));
+
+    (sender, task)
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut frame_writer: FrameWriter<W>,
+    mut receiver: mpsc::Receiver<IsoFrame>,
+) -> Result<(), FramingError> {
+    while let Some(frame) = receiver.recv().await {
+        queue(&mut frame_writer, frame);
+
+        for _ in 1..MAX_BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(frame) => queue(&mut frame_writer, frame),
+                Err(_) => break,
+            }
+        }
+
+        frame_writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+fn queue<W: AsyncWrite + Unpin>(frame_writer: &mut FrameWriter<W>, frame: IsoFrame) {
+    // Only this frame is lost, the connection itself is still fine
+    if let Err(error) = frame_writer.queue(frame) {
+        warn!("Unable to send response: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use bytes::BytesMut;
+    use tokio::io::{self, AsyncReadExt, AsyncWrite};
+    use tokio_util::codec::Decoder;
+
+    use super::spawn;
+    use crate::{
+        message_machine::{FrameWriter, FramingError, IsoFrame, IsoFrameCodec},
+        test_support::get_buffer_from_file,
+    };
+
+    fn get_frame_from_file(path: &str) -> IsoFrame {
+        let buffer = get_buffer_from_file(path);
+
+        IsoFrameCodec::default()
+            .decode(&mut BytesMut::from(&buffer[..]))
+            .unwrap()
+            .unwrap()
+    }
+
+    struct BrokenWriter;
+
+    impl AsyncWrite for BrokenWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_write_queued_frames_in_order() {
+        let path = "sample_messages/i2c-authorization-advice-request.bin";
+        let (writer, mut peer) = io::duplex(64 * 1024);
+        let (sender, task) = spawn(FrameWriter::new(writer, IsoFrameCodec::default()), 4);
+
+        for _ in 0..3 {
+            sender.send(get_frame_from_file(path)).await.unwrap();
+        }
+        drop(sender);
+        task.await.unwrap().unwrap();
+
+        let mut written = Vec::new();
+        peer.read_to_end(&mut written).await.unwrap();
+
+        assert_eq!(written, get_buffer_from_file(path).repeat(3));
+    }
+
+    #[tokio::test]
+    async fn should_close_queue_on_write_error() {
+        let path = "sample_messages/i2c-authorization-advice-request.bin";
+        let (sender, task) = spawn(FrameWriter::new(BrokenWriter, IsoFrameCodec::default()), 4);
+
+        sender.send(get_frame_from_file(path)).await.unwrap();
+
+        assert!(matches!(task.await.unwrap(), Err(FramingError::Io(_))));
+        assert!(sender.send(get_frame_from_file(path)).await.is_err());
+    }
+}
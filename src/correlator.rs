@@ -0,0 +1,390 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use iso_8583_message::IsoMessage;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::codec::FramedRead;
+use tracing::{debug, warn};
+
+use crate::{
+    connection_writer,
+    message_machine::{FrameWriter, IsoFrame, IsoFrameCodec},
+};
+
+/// How long `Correlator::send` waits for a response unless told otherwise.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Requests that may be waiting to be written.
+const REQUEST_QUEUE_SIZE: usize = 256;
+/// Events kept for subscribers that have fallen behind.
+const EVENT_CAPACITY: usize = 64;
+
+/// What ties a response to its request: the first three digits of the MTI with the third
+/// rounded down to even, so `0100`, `0101` and `0110` share the class `010` while advices
+/// like `0120` and `0130` have `012` of their own, then fields 11, 7 and 37.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CorrelationKey {
+    pub mti_class: String,
+    pub stan: Option<String>,
+    pub transmission_datetime: Option<String>,
+    pub rrn: Option<String>,
+}
+
+impl CorrelationKey {
+    pub fn of(message: &IsoMessage) -> Option<Self> {
+        let mti = message.get_field(0)?;
+        // Odd functions are the responses to the even ones before them
+        let function = mti.get(2..3)?.parse::<u8>().ok()?;
+        let field = |number: usize| message.get_field(number).map(str::to_string);
+
+        Some(Self {
+            mti_class: format!("{}{}", mti.get(..2)?, function & !1),
+            stan: field(11),
+            transmission_datetime: field(7),
+            rrn: field(37),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CorrelatorEvent {
+    /// A response arrived that no request in flight was waiting for, usually because it came
+    /// after the request timed out.
+    UnmatchedResponse(IsoMessage),
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// No response arrived in time.
+    Timeout,
+    /// A request with the same `CorrelationKey` is still waiting for its response.
+    InFlight,
+    /// The request could not be built or has no MTI to correlate on.
+    Invalid(String),
+    /// The connection closed before the response arrived.
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Timeout => write!(f, "no response before the timeout"),
+            SendError::InFlight => write!(f, "a request with the same key is already in flight"),
+            SendError::Invalid(reason) => write!(f, "invalid request: {}", reason),
+            SendError::Closed => write!(f, "connection closed before the response arrived"),
+        }
+    }
+}
+
+impl Error for SendError {}
+
+type InFlight = Arc<Mutex<Waiting>>;
+
+/// The requests waiting for a response, by key.
+#[derive(Debug, Default)]
+struct Waiting {
+    senders: HashMap<CorrelationKey, oneshot::Sender<IsoMessage>>,
+    /// Set once the host has closed the connection, after which nothing more is answered.
+    closed: bool,
+}
+
+/// A request's place in `InFlight`, given up however `send` ends, including when the caller
+/// stops waiting for it.
+struct Pending<'a> {
+    in_flight: &'a InFlight,
+    key: CorrelationKey,
+    response: oneshot::Receiver<IsoMessage>,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.response.close();
+
+        let mut in_flight = self.in_flight.lock().expect("correlator poisoned");
+
+        // Once answered, the key may already belong to a later request with the same ids
+        if matches!(in_flight.senders.get(&self.key), Some(sender) if sender.is_closed()) {
+            in_flight.senders.remove(&self.key);
+        }
+    }
+}
+
+/// Sends requests over one connection and hands each caller the response that matches its
+/// request, so many requests can be in flight at once.
+pub struct Correlator {
+    in_flight: InFlight,
+    requests: mpsc::Sender<IsoFrame>,
+    events: broadcast::Sender<CorrelatorEvent>,
+    reader: JoinHandle<()>,
+    timeout: Duration,
+}
+
+impl Correlator {
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        codec: IsoFrameCodec,
+    ) -> Result<Self, io::Error> {
+        Ok(Self::new(TcpStream::connect(addr).await?, codec))
+    }
+
+    /// Takes over `stream`, reading responses and writing requests on tasks of their own.
+    pub fn new<S>(stream: S, codec: IsoFrameCodec) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = io::split(stream);
+        let (requests, _) =
+            connection_writer::spawn(FrameWriter::new(writer, codec.clone()), REQUEST_QUEUE_SIZE);
+        let in_flight = InFlight::default();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let reader = tokio::spawn(read_responses(
+            FramedRead::new(reader, codec),
+            in_flight.clone(),
+            events.clone(),
+        ));
+
+        Self {
+            in_flight,
+            requests,
+            events,
+            reader,
+            timeout: RESPONSE_TIMEOUT,
+        }
+    }
+
+    /// How long `send` waits for a response. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Events from the time of subscribing on.
+    pub fn subscribe(&self) -> broadcast::Receiver<CorrelatorEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends `request` and waits for the response with the same `CorrelationKey`.
+    pub async fn send(&self, request: IsoMessage) -> Result<IsoMessage, SendError> {
+        let key = CorrelationKey::of(&request)
+            .ok_or_else(|| SendError::Invalid("request has no MTI".to_string()))?;
+        let (sender, response) = oneshot::channel();
+
+        {
+            let mut in_flight = self.in_flight.lock().expect("correlator poisoned");
+
+            if in_flight.closed {
+                return Err(SendError::Closed);
+            }
+            if in_flight.senders.contains_key(&key) {
+                return Err(SendError::InFlight);
+            }
+            in_flight.senders.insert(key.clone(), sender);
+        }
+
+        let mut pending = Pending {
+            in_flight: &self.in_flight,
+            key,
+            response,
+        };
+
+        if self.requests.send(IsoFrame::from(request)).await.is_err() {
+            return Err(SendError::Closed);
+        }
+
+        match timeout(self.timeout, &mut pending.response).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(SendError::Closed),
+            Err(_) => Err(SendError::Timeout),
+        }
+    }
+}
+
+impl Drop for Correlator {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_responses<R: AsyncRead + Unpin>(
+    mut reader: FramedRead<R, IsoFrameCodec>,
+    in_flight: InFlight,
+    events: broadcast::Sender<CorrelatorEvent>,
+) {
+    while let Some(frame) = reader.next().await {
+        let message = match frame {
+            Ok(frame) => frame.message,
+            Err(error) => {
+                warn!("Skipping frame: {}", error);
+                continue;
+            }
+        };
+
+        if !is_response(&message) {
+            warn!("Ignoring {:?} request from host", message.get_field(0));
+            continue;
+        }
+
+        let waiting = CorrelationKey::of(&message).and_then(|key| {
+            let mut in_flight = in_flight.lock().expect("correlator poisoned");
+            in_flight.senders.remove(&key)
+        });
+
+        match waiting {
+            Some(sender) => {
+                // The caller may have given up already, which makes this a late response too
+                if let Err(message) = sender.send(message) {
+                    let _ = events.send(CorrelatorEvent::UnmatchedResponse(message));
+                }
+            }
+            None => {
+                debug!("Response {:?} matches no request", message.get_field(0));
+                let _ = events.send(CorrelatorEvent::UnmatchedResponse(message));
+            }
+        }
+    }
+
+    debug!("Host closed the connection");
+
+    // Dropping the senders fails every request still waiting
+    let mut in_flight = in_flight.lock().expect("correlator poisoned");
+    in_flight.closed = true;
+    in_flight.senders.clear();
+}
+
+/// Responses have an odd third MTI digit, e.g. `0110` or `0430`.
+pub(crate) fn is_response(message: &IsoMessage) -> bool {
+    match message.get_field(0).and_then(|mti| mti.as_bytes().get(2)) {
+        Some(digit) => digit.is_ascii_digit() && (digit - b'0') % 2 == 1,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::duplex,
+        time::{sleep, timeout},
+    };
+    use tokio_util::codec::Framed;
+
+    use super::{CorrelationKey, Correlator, CorrelatorEvent, SendError};
+    use crate::{
+        message_machine::{IsoFrame, IsoFrameCodec},
+        test_support::get_message_from_file,
+    };
+
+    #[test]
+    fn should_keep_advices_apart_from_requests_with_same_ids() {
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let key = |mti: &str| {
+            let mut message = request.clone();
+            message.set_field(0, mti).unwrap();
+            CorrelationKey::of(&message).unwrap()
+        };
+
+        assert_eq!(key("0100"), key("0110"));
+        assert_eq!(key("0120"), key("0130"));
+        assert_ne!(key("0100"), key("0120"));
+        assert_eq!(key("0120").mti_class, "012");
+    }
+
+    #[tokio::test]
+    async fn should_match_responses_sent_out_of_order() {
+        let (ours, theirs) = duplex(64 * 1024);
+        let correlator = Correlator::new(ours, IsoFrameCodec::default());
+        let mut host = Framed::new(theirs, IsoFrameCodec::default());
+        let first = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let mut second = first.clone();
+        second.set_field(11, "016373").unwrap();
+
+        let host = tokio::spawn(async move {
+            let first = host.next().await.unwrap().unwrap().message;
+            let second = host.next().await.unwrap().unwrap().message;
+
+            for (request, response_code) in [(second, "05"), (first, "00")] {
+                let response = IsoFrame::from(request.to_response(response_code).unwrap());
+                host.send(response).await.unwrap();
+            }
+        });
+
+        let (first, second) = tokio::join!(correlator.send(first), correlator.send(second));
+        host.await.unwrap();
+
+        assert_eq!(first.unwrap().get_field(39), Some("00"));
+        assert_eq!(second.unwrap().get_field(39), Some("05"));
+    }
+
+    #[tokio::test]
+    async fn should_free_key_when_caller_stops_waiting() {
+        let (ours, _theirs) = duplex(64 * 1024);
+        let correlator =
+            Correlator::new(ours, IsoFrameCodec::default()).timeout(Duration::from_millis(10));
+        let request = get_message_from_file("sample_messages/i2c-financial-request.bin");
+
+        assert!(
+            timeout(Duration::from_millis(1), correlator.send(request.clone()))
+                .await
+                .is_err()
+        );
+
+        assert!(matches!(
+            correlator.send(request).await,
+            Err(SendError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_fail_sends_once_host_has_closed() {
+        let (ours, theirs) = duplex(64 * 1024);
+        let correlator = Correlator::new(ours, IsoFrameCodec::default());
+        let request = get_message_from_file("sample_messages/i2c-financial-request.bin");
+        drop(theirs);
+
+        while !correlator.reader.is_finished() {
+            sleep(Duration::from_millis(1)).await;
+        }
+        let sent = timeout(Duration::from_secs(1), correlator.send(request)).await;
+
+        assert!(matches!(sent, Ok(Err(SendError::Closed))));
+    }
+
+    #[tokio::test]
+    async fn should_report_late_response_as_unmatched() {
+        let (ours, theirs) = duplex(64 * 1024);
+        let correlator =
+            Correlator::new(ours, IsoFrameCodec::default()).timeout(Duration::from_millis(10));
+        let mut events = correlator.subscribe();
+        let mut host = Framed::new(theirs, IsoFrameCodec::default());
+        let request = get_message_from_file("sample_messages/i2c-financial-request.bin");
+
+        assert!(matches!(
+            correlator.send(request).await,
+            Err(SendError::Timeout)
+        ));
+
+        let request = host.next().await.unwrap().unwrap().message;
+        host.send(IsoFrame::from(request.to_response("00").unwrap()))
+            .await
+            .unwrap();
+
+        match events.recv().await.unwrap() {
+            CorrelatorEvent::UnmatchedResponse(response) => {
+                assert_eq!(response.get_field(0), Some("0210"))
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use iso_8583_message::IsoMessage;
+use tokio::{sync::OnceCell, time::Instant};
+use tracing::debug;
+
+use crate::message_handler::MessageHandler;
+
+/// What makes two requests the same transmission: the MTI without its repeat flag, then
+/// fields 11, 37, 41, 42 and 7.
+type TransmissionKey = [Option<String>; 6];
+
+type CachedResponse = Arc<OnceCell<Option<IsoMessage>>>;
+
+#[derive(Default)]
+struct Seen {
+    responses: HashMap<TransmissionKey, CachedResponse>,
+    /// Keys in the order they were first seen, to forget them once the window has passed.
+    expiry: VecDeque<(Instant, TransmissionKey)>,
+}
+
+/// Answers retransmitted 0100 and 0200 requests, and their 0101 and 0201 repeats, with the
+/// response to the first copy instead of handing them to `inner` again, as long as they
+/// arrive within `window` of it.
+///
+/// A copy that arrives while the first is still being handled waits for its response.
+pub struct Duplicates {
+    window: Duration,
+    seen: Mutex<Seen>,
+    inner: Box<dyn MessageHandler>,
+}
+
+impl Duplicates {
+    pub fn new(window: Duration, inner: impl MessageHandler + 'static) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(Seen::default()),
+            inner: Box::new(inner),
+        }
+    }
+
+    /// The response cell for `key`, and whether it was already there.
+    fn response_for(&self, key: TransmissionKey) -> (CachedResponse, bool) {
+        let mut seen = self.seen.lock().expect("duplicates poisoned");
+        let now = Instant::now();
+
+        while let Some((first_seen, _)) = seen.expiry.front() {
+            if now.duration_since(*first_seen) < self.window {
+                break;
+            }
+            if let Some((_, expired)) = seen.expiry.pop_front() {
+                seen.responses.remove(&expired);
+            }
+        }
+
+        if let Some(response) = seen.responses.get(&key) {
+            return (response.clone(), true);
+        }
+
+        let response = CachedResponse::default();
+        seen.responses.insert(key.clone(), response.clone());
+        seen.expiry.push_back((now, key));
+
+        (response, false)
+    }
+}
+
+fn transmission_key(request: &IsoMessage) -> Option<TransmissionKey> {
+    let mti = match request.get_field(0)? {
+        "0100" | "0101" => "0100",
+        "0200" | "0201" => "0200",
+        _ => return None,
+    };
+    let field = |number: usize| request.get_field(number).map(str::to_string);
+
+    Some([
+        Some(mti.to_string()),
+        field(11),
+        field(37),
+        field(41),
+        field(42),
+        field(7),
+    ])
+}
+
+#[async_trait]
+impl MessageHandler for Duplicates {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        let key = match transmission_key(request) {
+            Some(key) => key,
+            None => return self.inner.handle(request).await,
+        };
+        let (response, duplicate) = self.response_for(key);
+
+        if duplicate {
+            debug!("Answering duplicate transmission with the original response");
+        }
+
+        response
+            .get_or_init(|| self.inner.handle(request))
+            .await
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use iso_8583_message::IsoMessage;
+    use tokio::time::sleep;
+
+    use super::Duplicates;
+    use crate::{
+        ledger::{Account, Ledger},
+        message_handler::Approve,
+        test_support::get_message_from_file,
+        MessageHandler,
+    };
+
+    const PAN: &str = "100194868736564";
+
+    fn ledger() -> Ledger {
+        let account = Account {
+            pan: PAN.to_string(),
+            balance: 80_000,
+            expiry: None,
+        };
+
+        Ledger::new(vec![account], Approve::new(Duration::ZERO))
+    }
+
+    async fn response_code(handler: &impl MessageHandler, request: &IsoMessage) -> String {
+        let response = handler.handle(request).await.unwrap();
+
+        response.get_field(39).unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn should_answer_retransmission_with_original_response() {
+        let duplicates = Duplicates::new(Duration::from_secs(60), ledger());
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let mut repeat = request.clone();
+        repeat.set_field(0, "0101").unwrap();
+
+        assert_eq!(response_code(&duplicates, &request).await, "00");
+        assert_eq!(response_code(&duplicates, &request).await, "00");
+        assert_eq!(response_code(&duplicates, &repeat).await, "00");
+    }
+
+    #[tokio::test]
+    async fn should_process_different_stan() {
+        let duplicates = Duplicates::new(Duration::from_secs(60), ledger());
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let mut next = request.clone();
+        next.set_field(11, "016373").unwrap();
+
+        assert_eq!(response_code(&duplicates, &request).await, "00");
+        assert_eq!(response_code(&duplicates, &next).await, "51");
+    }
+
+    #[tokio::test]
+    async fn should_process_copies_outside_window() {
+        let duplicates = Duplicates::new(Duration::from_millis(10), ledger());
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        assert_eq!(response_code(&duplicates, &request).await, "00");
+        sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(response_code(&duplicates, &request).await, "51");
+    }
+}
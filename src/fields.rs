@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use iso_8583_message::IsoMessage;
+
+/// A message as field numbers and values, the MTI being field 0. This is the shape of
+/// `sample_messages/financial-advice.json`. The bitmap is left out since it follows from the
+/// fields that are set.
+pub type Fields = BTreeMap<usize, String>;
+
+/// Highest field number with a secondary bitmap.
+const LAST_FIELD: usize = 128;
+
+pub fn to_fields(message: &IsoMessage) -> Fields {
+    (0..=LAST_FIELD)
+        .filter(|field| *field != 1)
+        .filter_map(|field| {
+            message
+                .get_field(field)
+                .map(|value| (field, value.to_string()))
+        })
+        .collect()
+}
+
+pub fn from_fields(fields: &Fields) -> Result<IsoMessage, String> {
+    let mti = fields.get(&0).ok_or("field 0 (the MTI) is missing")?;
+    let mut message = IsoMessage::new(mti)?;
+
+    for (field, value) in fields.range(2..) {
+        message.set_field(*field, value)?;
+    }
+
+    Ok(message)
+}
+
+/// The message as a JSON object of field numbers to values, one field per line.
+pub fn to_json(message: &IsoMessage) -> String {
+    serde_json::to_string_pretty(&to_fields(message)).expect("fields always serialize")
+}
+
+pub fn from_json(json: &str) -> Result<IsoMessage, String> {
+    let fields: Fields = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+    from_fields(&fields)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{from_fields, from_json, to_fields, to_json};
+    use crate::test_support::get_message_from_file;
+
+    #[test]
+    fn should_round_trip_through_fields() {
+        let message = get_message_from_file("sample_messages/i2c-network-request.bin");
+
+        let fields = to_fields(&message);
+
+        assert_eq!(fields.get(&70).map(String::as_str), Some("081"));
+        assert!(!fields.contains_key(&1));
+        assert_eq!(from_fields(&fields).unwrap(), message);
+    }
+
+    #[test]
+    fn should_round_trip_through_json() {
+        let json = std::fs::read_to_string("sample_messages/financial-advice.json").unwrap();
+
+        let message = from_json(&json).unwrap();
+
+        assert_eq!(message.get_field(0), Some("0220"));
+        assert_eq!(to_json(&message).trim_end(), json.trim_end());
+    }
+}
@@ -0,0 +1,451 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use async_trait::async_trait;
+use iso_8583_message::IsoMessage;
+use tracing::{debug, warn};
+
+use crate::message_handler::MessageHandler;
+
+/// Response code for an advice or reversal whose original is not in the journal.
+const NO_ORIGINAL: &str = "25";
+/// Accepted requests kept for matching, oldest dropped first.
+const JOURNAL_SIZE: usize = 10_000;
+
+/// The fields that tie an advice or reversal to the request it refers to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionIds {
+    /// Field 11.
+    pub stan: Option<String>,
+    /// Field 7, `MMDDhhmmss`.
+    pub transmission_datetime: Option<String>,
+    /// Field 37.
+    pub rrn: Option<String>,
+}
+
+impl TransactionIds {
+    /// The ids of `request` itself.
+    pub fn of(request: &IsoMessage) -> Self {
+        Self {
+            stan: request.get_field(11).map(str::to_string),
+            transmission_datetime: request.get_field(7).map(str::to_string),
+            rrn: request.get_field(37).map(str::to_string),
+        }
+    }
+
+    /// The ids of the request an advice or reversal refers to. The STAN and transmission
+    /// datetime come from the original data elements in field 90 when it is present, and the
+    /// RRN is expected to be the original's.
+    pub fn original(request: &IsoMessage) -> Self {
+        let mut ids = Self::of(request);
+
+        // Original MTI, STAN, transmission datetime, then acquirer and forwarder ids
+        let original = request
+            .get_field(90)
+            .filter(|original| original.is_ascii())
+            .and_then(|original| Some((original.get(4..10)?, original.get(10..20)?)));
+
+        match original {
+            Some((stan, transmission_datetime)) => {
+                ids.stan = Some(stan.to_string());
+                ids.transmission_datetime = Some(transmission_datetime.to_string());
+            }
+            // Our own STAN and datetime are not the original's
+            None => {
+                ids.stan = None;
+                ids.transmission_datetime = None;
+            }
+        }
+
+        ids
+    }
+
+    /// Whether these ids, read with `original` from a follow-up, point at `candidate`.
+    pub fn refers_to(&self, candidate: &TransactionIds) -> bool {
+        let same =
+            |ours: &Option<String>, theirs: &Option<String>| ours.is_some() && ours == theirs;
+
+        (same(&self.stan, &candidate.stan)
+            && same(
+                &self.transmission_datetime,
+                &candidate.transmission_datetime,
+            ))
+            || same(&self.rrn, &candidate.rrn)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Applied {
+    /// `inner` is handling the follow-up and has not answered yet.
+    Pending,
+    /// `inner` answered the follow-up with this response code.
+    Answered(String),
+}
+
+#[derive(Debug)]
+struct JournalEntry {
+    ids: TransactionIds,
+    pan: Option<String>,
+    completion: Option<Applied>,
+    reversal: Option<Applied>,
+}
+
+impl JournalEntry {
+    fn applied(&mut self, follow_up: FollowUp) -> &mut Option<Applied> {
+        match follow_up {
+            FollowUp::Completion => &mut self.completion,
+            FollowUp::Reversal => &mut self.reversal,
+        }
+    }
+}
+
+/// Remembers accepted 0100, 0120 and 0200 requests so 0220 completions and 0420 reversals can
+/// be matched to them before `inner` sees them.
+///
+/// A follow-up with no original gets response code 25. Repeats of a completion or reversal
+/// that was already applied get the same response code again without reaching `inner`, so
+/// a retransmitted reversal never releases funds twice. A repeat that arrives while `inner`
+/// still handles the first copy gets no response.
+pub struct Journal {
+    entries: Mutex<VecDeque<JournalEntry>>,
+    inner: Box<dyn MessageHandler>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FollowUp {
+    Completion,
+    Reversal,
+}
+
+enum Claimed<'a> {
+    /// The follow-up is new and ours to pass to `inner`.
+    New(Claim<'a>),
+    /// There is no original, or the follow-up was answered before with this response code.
+    Answer(String),
+    /// Another copy of the follow-up is with `inner` right now.
+    InProgress,
+}
+
+/// A follow-up being handled by `inner`. Dropping it without calling `applied`, because
+/// `inner` gave no response or the handler was aborted, releases the original for the next
+/// copy of the follow-up.
+struct Claim<'a> {
+    journal: &'a Journal,
+    ids: TransactionIds,
+    pan: Option<String>,
+    follow_up: FollowUp,
+    response_code: Option<String>,
+}
+
+impl Claim<'_> {
+    fn applied(mut self, response_code: &str) {
+        self.response_code = Some(response_code.to_string());
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.journal.entries.lock().expect("journal poisoned");
+
+        if let Some(entry) = find_original(&mut entries, &self.ids, self.pan.as_deref()) {
+            *entry.applied(self.follow_up) = self.response_code.take().map(Applied::Answered);
+        }
+    }
+}
+
+impl Journal {
+    pub fn new(inner: impl MessageHandler + 'static) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            inner: Box::new(inner),
+        }
+    }
+
+    fn record(&self, request: &IsoMessage) {
+        let mut entries = self.entries.lock().expect("journal poisoned");
+
+        if entries.len() == JOURNAL_SIZE {
+            entries.pop_front();
+        }
+        entries.push_back(JournalEntry {
+            ids: TransactionIds::of(request),
+            pan: request.get_field(2).map(str::to_string),
+            completion: None,
+            reversal: None,
+        });
+    }
+
+    /// Claims the original for `request`, marking the follow-up pending so a repeat that
+    /// arrives while `inner` handles it is not applied as well.
+    fn claim(&self, request: &IsoMessage, follow_up: FollowUp) -> Claimed<'_> {
+        let ids = TransactionIds::original(request);
+        let pan = request.get_field(2);
+        let mut entries = self.entries.lock().expect("journal poisoned");
+
+        let entry = match find_original(&mut entries, &ids, pan) {
+            Some(entry) => entry,
+            None => return Claimed::Answer(NO_ORIGINAL.to_string()),
+        };
+        let applied = entry.applied(follow_up);
+
+        match applied {
+            Some(Applied::Answered(response_code)) => Claimed::Answer(response_code.clone()),
+            Some(Applied::Pending) => Claimed::InProgress,
+            None => {
+                *applied = Some(Applied::Pending);
+                Claimed::New(Claim {
+                    journal: self,
+                    ids,
+                    pan: pan.map(str::to_string),
+                    follow_up,
+                    response_code: None,
+                })
+            }
+        }
+    }
+}
+
+/// The latest entry for the same card that `ids`, read from a follow-up, point at.
+fn find_original<'a>(
+    entries: &'a mut VecDeque<JournalEntry>,
+    ids: &TransactionIds,
+    pan: Option<&str>,
+) -> Option<&'a mut JournalEntry> {
+    entries
+        .iter_mut()
+        .rev()
+        .find(|entry| ids.refers_to(&entry.ids) && entry.pan.as_deref() == pan)
+}
+
+#[async_trait]
+impl MessageHandler for Journal {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        let follow_up = match request.get_field(0)? {
+            "0100" | "0120" | "0200" => {
+                let response = self.inner.handle(request).await?;
+
+                if response.get_field(39) == Some("00") {
+                    self.record(request);
+                }
+
+                return Some(response);
+            }
+            "0220" | "0221" => FollowUp::Completion,
+            "0420" | "0421" => FollowUp::Reversal,
+            _ => return self.inner.handle(request).await,
+        };
+
+        let claim = match self.claim(request, follow_up) {
+            Claimed::New(claim) => claim,
+            Claimed::Answer(response_code) => {
+                debug!("Answering follow-up from journal with {}", response_code);
+
+                return match request.to_response(&response_code) {
+                    Ok(response) => Some(response),
+                    Err(e) => {
+                        warn!("Unable to build response: {}", e);
+                        None
+                    }
+                };
+            }
+            Claimed::InProgress => {
+                // The peer repeats it again if the first copy's response does not reach it
+                debug!("Not answering repeat of a follow-up still being applied");
+                return None;
+            }
+        };
+
+        let response = self.inner.handle(request).await?;
+
+        if let Some(response_code) = response.get_field(39) {
+            claim.applied(response_code);
+        }
+
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use iso_8583_message::IsoMessage;
+    use tokio::time::{sleep, timeout};
+
+    use super::{Journal, TransactionIds};
+    use crate::{
+        ledger::{Account, Ledger},
+        message_handler::Approve,
+        test_support::get_message_from_file,
+        MessageHandler,
+    };
+
+    /// The sample reversal, pointed at the sample authorization through field 90.
+    fn reversal_of(original: &IsoMessage) -> IsoMessage {
+        let mut reversal = get_message_from_file("sample_messages/i2c-reversal-request.bin");
+        let original_data = format!(
+            "0100{}{}{:0>22}",
+            original.get_field(11).unwrap(),
+            original.get_field(7).unwrap(),
+            0
+        );
+        reversal
+            .set_field(2, original.get_field(2).unwrap())
+            .unwrap();
+        reversal.set_field(90, &original_data).unwrap();
+
+        reversal
+    }
+
+    /// Gives no response until told to answer, then approves after `delay`.
+    struct Silent {
+        answer: Arc<AtomicBool>,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl MessageHandler for Silent {
+        async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+            sleep(self.delay).await;
+
+            match self.answer.load(Ordering::SeqCst) {
+                true => request.to_response("00").ok(),
+                false => None,
+            }
+        }
+    }
+
+    async fn response_code(handler: &impl MessageHandler, request: &IsoMessage) -> String {
+        let response = handler.handle(request).await.unwrap();
+
+        response.get_field(39).unwrap().to_string()
+    }
+
+    #[test]
+    fn should_read_original_ids_from_field_90() {
+        let original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let reversal = reversal_of(&original);
+
+        let ids = TransactionIds::original(&reversal);
+
+        assert_eq!(ids.stan.as_deref(), Some("016372"));
+        assert_eq!(ids.transmission_datetime.as_deref(), Some("0122132918"));
+        assert!(ids.refers_to(&TransactionIds::of(&original)));
+    }
+
+    #[test]
+    fn should_ignore_field_90_that_is_not_ascii() {
+        let mut reversal = get_message_from_file("sample_messages/i2c-reversal-request.bin");
+        reversal
+            .set_field(90, &format!("010001637é{:0>31}", 0))
+            .unwrap();
+
+        let ids = TransactionIds::original(&reversal);
+
+        assert_eq!(ids.stan, None);
+        assert_eq!(ids.transmission_datetime, None);
+    }
+
+    #[tokio::test]
+    async fn should_answer_reversal_without_original_with_25() {
+        let journal = Journal::new(Approve::new(Duration::ZERO));
+        let reversal = get_message_from_file("sample_messages/i2c-reversal-request.bin");
+
+        assert_eq!(response_code(&journal, &reversal).await, "25");
+    }
+
+    #[tokio::test]
+    async fn should_release_funds_once_for_repeated_reversal() {
+        let pan = "100194868736564";
+        let account = Account {
+            pan: pan.to_string(),
+            balance: 80_000,
+            expiry: None,
+        };
+        let journal = Journal::new(Ledger::new(vec![account], Approve::new(Duration::ZERO)));
+        let original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let reversal = reversal_of(&original);
+
+        assert_eq!(response_code(&journal, &original).await, "00");
+        assert_eq!(response_code(&journal, &reversal).await, "00");
+        let mut repeat = reversal.clone();
+        repeat.set_field(0, "0421").unwrap();
+        assert_eq!(response_code(&journal, &repeat).await, "00");
+
+        // Holding 50 000 again would leave 30 000 if the reversal had only been applied once
+        assert_eq!(response_code(&journal, &original).await, "00");
+        assert_eq!(response_code(&journal, &original).await, "51");
+    }
+
+    #[tokio::test]
+    async fn should_not_record_declined_requests() {
+        let journal = Journal::new(Approve::new(Duration::ZERO).response_code("05"));
+        let original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let reversal = reversal_of(&original);
+
+        response_code(&journal, &original).await;
+
+        assert_eq!(response_code(&journal, &reversal).await, "25");
+    }
+
+    #[tokio::test]
+    async fn should_release_claim_when_inner_gives_no_response() {
+        let answer = Arc::new(AtomicBool::new(true));
+        let journal = Journal::new(Silent {
+            answer: answer.clone(),
+            delay: Duration::ZERO,
+        });
+        let original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let reversal = reversal_of(&original);
+        response_code(&journal, &original).await;
+
+        answer.store(false, Ordering::SeqCst);
+        assert!(journal.handle(&reversal).await.is_none());
+        answer.store(true, Ordering::SeqCst);
+
+        assert_eq!(response_code(&journal, &reversal).await, "00");
+    }
+
+    #[tokio::test]
+    async fn should_release_claim_when_handler_is_dropped() {
+        let journal = Journal::new(Silent {
+            answer: Arc::new(AtomicBool::new(true)),
+            delay: Duration::from_millis(50),
+        });
+        let original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let reversal = reversal_of(&original);
+        response_code(&journal, &original).await;
+
+        assert!(timeout(Duration::from_millis(5), journal.handle(&reversal))
+            .await
+            .is_err());
+
+        assert_eq!(response_code(&journal, &reversal).await, "00");
+    }
+
+    #[tokio::test]
+    async fn should_not_mark_original_of_another_card() {
+        let answer = Arc::new(AtomicBool::new(true));
+        let journal = Journal::new(Silent {
+            answer: answer.clone(),
+            delay: Duration::ZERO,
+        });
+        let original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let mut other_card = original.clone();
+        other_card.set_field(2, "4000000000000002").unwrap();
+        response_code(&journal, &original).await;
+        response_code(&journal, &other_card).await;
+
+        assert_eq!(response_code(&journal, &reversal_of(&original)).await, "00");
+
+        // Answered by `inner`, not from the first card's entry
+        answer.store(false, Ordering::SeqCst);
+        assert!(journal.handle(&reversal_of(&other_card)).await.is_none());
+    }
+}
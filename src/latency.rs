@@ -0,0 +1,279 @@
+use std::{collections::BTreeMap, f64::consts::PI, sync::Mutex, time::Duration};
+
+use iso_8583_message::IsoMessage;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// Longest delay a profile may give, an hour.
+const MAX_DELAY_MS: u64 = 3_600_000;
+
+/// How long to wait before answering a request.
+///
+/// ```toml
+/// kind = "normal"
+/// mean_ms = 300
+/// std_dev_ms = 80
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Latency {
+    Fixed {
+        ms: u64,
+    },
+    /// Any delay between `min_ms` and `max_ms` with equal probability.
+    Uniform {
+        min_ms: u64,
+        max_ms: u64,
+    },
+    /// Normally distributed delay, never below zero.
+    Normal {
+        mean_ms: f64,
+        std_dev_ms: f64,
+    },
+    /// Delays read off `[percentile, ms]` points, e.g. `[[50, 120], [99, 900]]`, interpolating
+    /// between them.
+    Percentiles {
+        points: Vec<(f64, u64)>,
+    },
+    /// The request is never answered.
+    Never,
+}
+
+impl Latency {
+    /// Draws a delay, or `None` if the request should not be answered at all.
+    pub fn sample(&self, rng: &mut impl Rng) -> Option<Duration> {
+        let ms = match self {
+            Latency::Fixed { ms } => *ms as f64,
+            Latency::Uniform { min_ms, max_ms } => rng.gen_range(*min_ms..=*max_ms) as f64,
+            Latency::Normal {
+                mean_ms,
+                std_dev_ms,
+            } => {
+                // Box-Muller transform
+                let radius = (-2.0 * (1.0 - rng.gen::<f64>()).ln()).sqrt();
+                let angle = 2.0 * PI * rng.gen::<f64>();
+
+                mean_ms + std_dev_ms * radius * angle.cos()
+            }
+            Latency::Percentiles { points } => percentile(points, rng.gen_range(0.0..100.0)),
+            Latency::Never => return None,
+        };
+
+        // A normal distribution's tail can reach past any limit, and NaN becomes no delay
+        let ms = ms.clamp(0.0, MAX_DELAY_MS as f64);
+
+        Some(Duration::try_from_secs_f64(ms / 1_000.0).unwrap_or_default())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let too_long = |name: &str, ms: f64| {
+            Err(format!(
+                "{} must be a number of milliseconds up to {}, got {}",
+                name, MAX_DELAY_MS, ms
+            ))
+        };
+        let in_range = |ms: f64| ms.is_finite() && ms <= MAX_DELAY_MS as f64;
+
+        match self {
+            Latency::Fixed { ms } if !in_range(*ms as f64) => too_long("ms", *ms as f64),
+            Latency::Uniform { max_ms, .. } if !in_range(*max_ms as f64) => {
+                too_long("max_ms", *max_ms as f64)
+            }
+            Latency::Uniform { min_ms, max_ms } if min_ms > max_ms => {
+                Err(format!("min_ms {} is above max_ms {}", min_ms, max_ms))
+            }
+            Latency::Normal { mean_ms, .. } if !in_range(mean_ms.abs()) => {
+                too_long("mean_ms", *mean_ms)
+            }
+            Latency::Normal { std_dev_ms, .. } if !in_range(*std_dev_ms) => {
+                too_long("std_dev_ms", *std_dev_ms)
+            }
+            Latency::Normal { std_dev_ms, .. } if *std_dev_ms < 0.0 => {
+                Err("std_dev_ms must not be negative".to_string())
+            }
+            Latency::Percentiles { points } if points.is_empty() => {
+                Err("at least one percentile point is required".to_string())
+            }
+            Latency::Percentiles { points } => {
+                let in_order = points
+                    .windows(2)
+                    .all(|pair| pair[0].0 < pair[1].0 && pair[0].1 <= pair[1].1);
+                let percentiles_in_range = points
+                    .iter()
+                    .all(|(percentile, _)| (0.0..=100.0).contains(percentile));
+
+                if let Some((_, ms)) = points.iter().find(|(_, ms)| !in_range(*ms as f64)) {
+                    too_long("percentile point", *ms as f64)
+                } else if in_order && percentiles_in_range {
+                    Ok(())
+                } else {
+                    Err("percentile points must be between 0 and 100 and increasing".to_string())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The delay at `at` percent, interpolated between the surrounding points.
+fn percentile(points: &[(f64, u64)], at: f64) -> f64 {
+    let upper = points
+        .iter()
+        .position(|(percentile, _)| *percentile >= at)
+        .unwrap_or(points.len() - 1);
+
+    if upper == 0 || points[upper].0 < at {
+        return points[upper].1 as f64;
+    }
+
+    let (low_percentile, low_ms) = points[upper - 1];
+    let (high_percentile, high_ms) = points[upper];
+    let position = (at - low_percentile) / (high_percentile - low_percentile);
+
+    low_ms as f64 + position * (high_ms as f64 - low_ms as f64)
+}
+
+/// Picks the latency for each request from a per-MTI table, sampling with one RNG so a seeded
+/// run draws the same delays again.
+#[derive(Debug)]
+pub struct LatencyProfiles {
+    default: Latency,
+    by_mti: BTreeMap<String, Latency>,
+    rng: Mutex<StdRng>,
+}
+
+impl LatencyProfiles {
+    /// Unseeded profiles, so delays differ from run to run.
+    pub fn new(default: Latency, by_mti: BTreeMap<String, Latency>) -> Self {
+        Self {
+            default,
+            by_mti,
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    pub fn seed(self, seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            ..self
+        }
+    }
+
+    /// Samples `overriding` if given, which is how rules pick their own latency, and otherwise
+    /// the profile for the request's MTI or the default.
+    pub fn sample(&self, request: &IsoMessage, overriding: Option<&Latency>) -> Option<Duration> {
+        let latency = overriding
+            .or_else(|| request.get_field(0).and_then(|mti| self.by_mti.get(mti)))
+            .unwrap_or(&self.default);
+        let mut rng = self.rng.lock().expect("latency rng poisoned");
+
+        latency.sample(&mut *rng)
+    }
+}
+
+impl Default for LatencyProfiles {
+    /// Answers immediately.
+    fn default() -> Self {
+        Self::new(Latency::Fixed { ms: 0 }, BTreeMap::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{percentile, Latency, MAX_DELAY_MS};
+
+    fn samples(latency: &Latency, seed: u64) -> Vec<Option<Duration>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        (0..100).map(|_| latency.sample(&mut rng)).collect()
+    }
+
+    #[test]
+    fn should_repeat_delays_with_same_seed() {
+        let latency = Latency::Normal {
+            mean_ms: 300.0,
+            std_dev_ms: 80.0,
+        };
+
+        assert_eq!(samples(&latency, 7), samples(&latency, 7));
+        assert_ne!(samples(&latency, 7), samples(&latency, 8));
+    }
+
+    #[test]
+    fn should_keep_uniform_delays_in_range() {
+        let latency = Latency::Uniform {
+            min_ms: 100,
+            max_ms: 200,
+        };
+
+        assert!(samples(&latency, 1).into_iter().all(|delay| {
+            let delay = delay.unwrap();
+            delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200)
+        }));
+    }
+
+    #[test]
+    fn should_reject_delays_out_of_range() {
+        let normal = |mean_ms: f64, std_dev_ms: f64| Latency::Normal {
+            mean_ms,
+            std_dev_ms,
+        };
+
+        assert!(normal(1e300, 0.0).validate().is_err());
+        assert!(normal(f64::INFINITY, 0.0).validate().is_err());
+        assert!(normal(300.0, f64::NAN).validate().is_err());
+        assert!(Latency::Fixed { ms: u64::MAX }.validate().is_err());
+        assert!(normal(300.0, 80.0).validate().is_ok());
+    }
+
+    #[test]
+    fn should_clamp_sampled_delays() {
+        let samples = samples(
+            &Latency::Normal {
+                mean_ms: 1e300,
+                std_dev_ms: f64::INFINITY,
+            },
+            10,
+        );
+
+        assert!(samples
+            .iter()
+            .all(|delay| *delay <= Some(Duration::from_millis(MAX_DELAY_MS))));
+    }
+
+    #[test]
+    fn should_never_answer_with_never() {
+        assert!(samples(&Latency::Never, 1).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn should_interpolate_between_percentiles() {
+        let points = [(50.0, 100), (90.0, 500), (100.0, 1_000)];
+
+        assert_eq!(percentile(&points, 10.0), 100.0);
+        assert_eq!(percentile(&points, 70.0), 300.0);
+        assert_eq!(percentile(&points, 95.0), 750.0);
+    }
+
+    #[test]
+    fn should_parse_from_toml() {
+        let latency: Latency =
+            toml::from_str("kind = \"percentiles\"\npoints = [[50, 120], [99, 900]]").unwrap();
+
+        assert_eq!(
+            latency,
+            Latency::Percentiles {
+                points: vec![(50.0, 120), (99.0, 900)]
+            }
+        );
+        assert!(Latency::Percentiles {
+            points: vec![(99.0, 900), (50.0, 120)]
+        }
+        .validate()
+        .is_err());
+    }
+}
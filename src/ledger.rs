@@ -0,0 +1,441 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use iso_8583_message::IsoMessage;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{config::ConfigError, journal::TransactionIds, message_handler::MessageHandler};
+
+const APPROVED: &str = "00";
+const INVALID_CARD: &str = "14";
+const INSUFFICIENT_FUNDS: &str = "51";
+const EXPIRED_CARD: &str = "54";
+/// Holds, and debits that may still be reversed, kept per card. The oldest is dropped first,
+/// so a hold beyond this many lapses and its funds become available again.
+const MAX_OPEN: usize = 1_000;
+
+/// A card in an accounts fixture, with amounts in minor units.
+///
+/// JSON fixtures are a list of accounts:
+///
+/// ```json
+/// [{ "pan": "100194868736564", "balance": 100000, "expiry": "2512" }]
+/// ```
+///
+/// CSV fixtures have a `pan,balance,expiry` header, and the expiry may be left empty.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+    pub pan: String,
+    pub balance: i64,
+    /// `YYMM`, like field 14. Cards without one never expire.
+    #[serde(default)]
+    pub expiry: Option<String>,
+}
+
+impl Account {
+    /// Reads a JSON fixture, or a CSV one if the file name ends in `.csv`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Account>, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+
+        if path.extension().and_then(|extension| extension.to_str()) == Some("csv") {
+            Self::from_csv(&contents)
+        } else {
+            serde_json::from_str(&contents).map_err(ConfigError::ParseJson)
+        }
+    }
+
+    pub fn from_csv(contents: &str) -> Result<Vec<Account>, ConfigError> {
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+
+        match lines.next() {
+            Some((_, header)) if header.trim() == "pan,balance,expiry" => {}
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "accounts CSV must start with a pan,balance,expiry header".to_string(),
+                ))
+            }
+        }
+
+        lines
+            .map(|(number, line)| {
+                let invalid =
+                    |reason: &str| ConfigError::Invalid(format!("line {}: {}", number + 1, reason));
+                let columns: Vec<&str> = line.split(',').map(str::trim).collect();
+
+                match columns[..] {
+                    [pan, balance, expiry] => Ok(Account {
+                        pan: pan.to_string(),
+                        balance: balance
+                            .parse()
+                            .map_err(|_| invalid("balance is not a number"))?,
+                        expiry: Some(expiry.to_string()).filter(|expiry| !expiry.is_empty()),
+                    }),
+                    _ => Err(invalid("expected 3 columns")),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Balances of one card, in minor units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balances {
+    /// Ledger balance less open holds.
+    pub available: i64,
+    /// Posted transactions only.
+    pub ledger: i64,
+}
+
+/// Amounts by the transaction they belong to, with their running total.
+#[derive(Debug, Default)]
+struct Amounts {
+    amounts: VecDeque<(TransactionIds, i64)>,
+    total: i64,
+}
+
+impl Amounts {
+    fn push(&mut self, ids: TransactionIds, amount: i64) {
+        if self.amounts.len() == MAX_OPEN {
+            if let Some((ids, lapsed)) = self.amounts.pop_front() {
+                debug!("Dropping amount {} of {:?}", lapsed, ids);
+                self.total -= lapsed;
+            }
+        }
+
+        self.amounts.push_back((ids, amount));
+        self.total += amount;
+    }
+
+    /// Removes the amount of the transaction `original` refers to, the latest if several do.
+    fn take(&mut self, original: &TransactionIds) -> Option<i64> {
+        let position = self
+            .amounts
+            .iter()
+            .rposition(|(ids, _)| original.refers_to(ids))?;
+        let (_, amount) = self.amounts.remove(position)?;
+        self.total -= amount;
+
+        Some(amount)
+    }
+}
+
+#[derive(Debug)]
+struct AccountState {
+    ledger: i64,
+    expiry: Option<String>,
+    /// Open authorization holds.
+    holds: Amounts,
+    /// Posted 0200 debits, kept so a reversal can credit them back.
+    debits: Amounts,
+}
+
+impl AccountState {
+    fn available(&self) -> i64 {
+        self.ledger - self.holds.total
+    }
+
+    fn is_expired(&self) -> bool {
+        match &self.expiry {
+            Some(expiry) => *expiry < Utc::now().format("%y%m").to_string(),
+            None => false,
+        }
+    }
+}
+
+/// In-memory accounts keyed by PAN. Answers authorizations, financial requests, completions
+/// and reversals from the balances, and hands every other MTI to `fallback`.
+///
+/// A 0220 or 0420 finds the hold or debit of the 0100 or 0200 it refers to by
+/// `TransactionIds`.
+pub struct Ledger {
+    accounts: Mutex<HashMap<String, AccountState>>,
+    fallback: Box<dyn MessageHandler>,
+}
+
+impl Ledger {
+    pub fn new(accounts: Vec<Account>, fallback: impl MessageHandler + 'static) -> Self {
+        let accounts = accounts
+            .into_iter()
+            .map(|account| {
+                let state = AccountState {
+                    ledger: account.balance,
+                    expiry: account.expiry,
+                    holds: Amounts::default(),
+                    debits: Amounts::default(),
+                };
+                (account.pan, state)
+            })
+            .collect();
+
+        Self {
+            accounts: Mutex::new(accounts),
+            fallback: Box::new(fallback),
+        }
+    }
+
+    pub fn balances(&self, pan: &str) -> Option<Balances> {
+        let accounts = self.accounts.lock().expect("ledger poisoned");
+
+        accounts.get(pan).map(|account| Balances {
+            available: account.available(),
+            ledger: account.ledger,
+        })
+    }
+
+    /// Applies `request` to its account and returns the response code, or `None` for MTIs
+    /// the ledger does not handle.
+    fn apply(&self, request: &IsoMessage) -> Option<&'static str> {
+        let mti = request.get_field(0)?;
+
+        if !matches!(
+            mti,
+            "0100" | "0120" | "0200" | "0220" | "0221" | "0420" | "0421"
+        ) {
+            return None;
+        }
+
+        let amount = request
+            .get_field(4)
+            .and_then(|amount| amount.parse::<i64>().ok())
+            .unwrap_or(0);
+        let ids = TransactionIds::of(request);
+        let original = TransactionIds::original(request);
+
+        let mut accounts = self.accounts.lock().expect("ledger poisoned");
+        let account = match request.get_field(2).and_then(|pan| accounts.get_mut(pan)) {
+            Some(account) => account,
+            None => return Some(INVALID_CARD),
+        };
+
+        let response_code = match mti {
+            // Advices tell us about something the acquirer already did, so they are never
+            // declined
+            "0120" => {
+                account.holds.push(ids, amount);
+                APPROVED
+            }
+            "0220" | "0221" => {
+                account.holds.take(&original);
+                account.ledger -= amount;
+                APPROVED
+            }
+            "0420" | "0421" => {
+                if account.holds.take(&original).is_none() {
+                    if let Some(debit) = account.debits.take(&original) {
+                        account.ledger += debit;
+                    }
+                }
+                APPROVED
+            }
+            _ if account.is_expired() => EXPIRED_CARD,
+            _ if amount > account.available() => INSUFFICIENT_FUNDS,
+            "0100" => {
+                account.holds.push(ids, amount);
+                APPROVED
+            }
+            _ => {
+                account.ledger -= amount;
+                account.debits.push(ids, amount);
+                APPROVED
+            }
+        };
+
+        debug!(
+            "Ledger answered {} with {}, available balance {}",
+            mti,
+            response_code,
+            account.available()
+        );
+
+        Some(response_code)
+    }
+}
+
+#[async_trait]
+impl MessageHandler for Ledger {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        let response_code = match self.apply(request) {
+            Some(response_code) => response_code,
+            None => return self.fallback.handle(request).await,
+        };
+
+        match request.to_response(response_code) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!("Unable to build response: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use iso_8583_message::IsoMessage;
+
+    use super::{Account, Balances, Ledger, MAX_OPEN};
+    use crate::{
+        config::ConfigError, message_handler::Approve, test_support::get_message_from_file,
+        MessageHandler,
+    };
+
+    const PAN: &str = "100194868736564";
+
+    fn ledger(balance: i64, expiry: Option<&str>) -> Ledger {
+        let account = Account {
+            pan: PAN.to_string(),
+            balance,
+            expiry: expiry.map(str::to_string),
+        };
+
+        Ledger::new(vec![account], Approve::new(Duration::ZERO))
+    }
+
+    async fn response_code(ledger: &Ledger, request: &IsoMessage) -> String {
+        let response = ledger.handle(request).await.unwrap();
+
+        response.get_field(39).unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn should_hold_approved_authorization() {
+        let ledger = ledger(80_000, None);
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        assert_eq!(response_code(&ledger, &request).await, "00");
+        assert_eq!(
+            ledger.balances(PAN),
+            Some(Balances {
+                available: 30_000,
+                ledger: 80_000
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn should_decline_authorization_over_available_balance() {
+        let ledger = ledger(80_000, None);
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        assert_eq!(response_code(&ledger, &request).await, "00");
+        assert_eq!(response_code(&ledger, &request).await, "51");
+    }
+
+    #[tokio::test]
+    async fn should_decline_unknown_and_expired_cards() {
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let unknown = get_message_from_file("sample_messages/i2c-financial-request.bin");
+
+        assert_eq!(
+            response_code(&ledger(80_000, Some("0001")), &request).await,
+            "54"
+        );
+        assert_eq!(response_code(&ledger(80_000, None), &unknown).await, "14");
+    }
+
+    #[tokio::test]
+    async fn should_release_hold_on_reversal() {
+        let ledger = ledger(80_000, None);
+        let mut request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let mut reversal = request.clone();
+        reversal.set_field(0, "0420").unwrap();
+        request.set_field(4, "000000020000").unwrap();
+
+        ledger.handle(&request).await.unwrap();
+        assert_eq!(ledger.balances(PAN).unwrap().available, 60_000);
+        ledger.handle(&reversal).await.unwrap();
+
+        assert_eq!(ledger.balances(PAN).unwrap().available, 80_000);
+    }
+
+    #[tokio::test]
+    async fn should_post_completion_against_hold() {
+        let ledger = ledger(80_000, None);
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let mut completion = request.clone();
+        completion.set_field(0, "0220").unwrap();
+        completion.set_field(4, "000000045000").unwrap();
+
+        ledger.handle(&request).await.unwrap();
+        ledger.handle(&completion).await.unwrap();
+
+        assert_eq!(
+            ledger.balances(PAN),
+            Some(Balances {
+                available: 35_000,
+                ledger: 35_000
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn should_post_repeated_completion_against_hold() {
+        let ledger = ledger(80_000, None);
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let mut completion = request.clone();
+        completion.set_field(0, "0221").unwrap();
+
+        ledger.handle(&request).await.unwrap();
+        ledger.handle(&completion).await.unwrap();
+
+        assert_eq!(
+            ledger.balances(PAN),
+            Some(Balances {
+                available: 30_000,
+                ledger: 30_000
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn should_let_oldest_holds_lapse() {
+        let ledger = ledger(i64::MAX, None);
+        let mut request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        request.set_field(4, "000000000001").unwrap();
+
+        for stan in 0..=MAX_OPEN {
+            request.set_field(11, &format!("{:06}", stan)).unwrap();
+            request.set_field(37, &format!("{:012}", stan)).unwrap();
+            ledger.handle(&request).await.unwrap();
+        }
+
+        assert_eq!(
+            ledger.balances(PAN).unwrap().available,
+            i64::MAX - MAX_OPEN as i64
+        );
+    }
+
+    #[tokio::test]
+    async fn should_pass_other_mtis_to_fallback() {
+        let request = get_message_from_file("sample_messages/i2c-network-request.bin");
+
+        assert_eq!(response_code(&ledger(0, None), &request).await, "00");
+    }
+
+    #[test]
+    fn should_read_csv_fixture() {
+        let accounts = Account::from_csv("pan,balance,expiry\n1234,500,2512\n5678,0,\n").unwrap();
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].expiry.as_deref(), Some("2512"));
+        assert_eq!(accounts[1].expiry, None);
+        assert!(matches!(
+            Account::from_csv("pan,balance,expiry\n1234,lots,\n"),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,321 @@
+use std::str::FromStr;
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::message_machine::FramingError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LengthEncoding {
+    BinaryBigEndian,
+    BinaryLittleEndian,
+    /// Decimal digits as ASCII characters, e.g. `"0506"`.
+    Ascii,
+    /// Two decimal digits packed into each byte, e.g. `0x05 0x06`.
+    Bcd,
+    /// Decimal digits as EBCDIC characters (`0xF0` - `0xF9`).
+    Ebcdic,
+}
+
+impl FromStr for LengthEncoding {
+    type Err = String;
+
+    /// Parses the names used in config files, e.g. `binary-big-endian` or `ascii`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary-big-endian" => Ok(LengthEncoding::BinaryBigEndian),
+            "binary-little-endian" => Ok(LengthEncoding::BinaryLittleEndian),
+            "ascii" => Ok(LengthEncoding::Ascii),
+            "bcd" => Ok(LengthEncoding::Bcd),
+            "ebcdic" => Ok(LengthEncoding::Ebcdic),
+            _ => Err(format!(
+                "unknown length encoding {:?}, expected one of binary-big-endian, \
+                 binary-little-endian, ascii, bcd or ebcdic",
+                s
+            )),
+        }
+    }
+}
+
+/// How the length of each frame is written in front of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthPrefix {
+    width: usize,
+    encoding: LengthEncoding,
+    inclusive: bool,
+}
+
+impl LengthPrefix {
+    /// A `width` byte prefix that counts only the message after it.
+    pub const fn new(width: usize, encoding: LengthEncoding) -> Self {
+        assert!(width > 0, "length prefix must be at least one byte wide");
+
+        Self {
+            width,
+            encoding,
+            inclusive: false,
+        }
+    }
+
+    /// The prefix counts its own bytes as well as the message after it.
+    pub const fn inclusive(mut self) -> Self {
+        self.inclusive = true;
+        self
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Reads the length of the message following the prefix at the start of `buf`.
+    pub fn read(&self, buf: &[u8]) -> Result<usize, FramingError> {
+        if buf.len() < self.width {
+            return Err(FramingError::ShortPrefix {
+                expected: self.width,
+                available: buf.len(),
+            });
+        }
+
+        let prefix = &buf[..self.width];
+        let invalid_prefix = || FramingError::InvalidPrefix {
+            prefix: prefix.to_vec(),
+        };
+
+        let value = match self.encoding {
+            LengthEncoding::BinaryBigEndian => prefix.iter().try_fold(0u64, |value, &byte| {
+                value.checked_mul(256)?.checked_add(byte as u64)
+            }),
+            LengthEncoding::BinaryLittleEndian => {
+                prefix.iter().rev().try_fold(0u64, |value, &byte| {
+                    value.checked_mul(256)?.checked_add(byte as u64)
+                })
+            }
+            LengthEncoding::Ascii => decimal(prefix.iter().map(|&byte| byte.checked_sub(b'0'))),
+            LengthEncoding::Bcd => decimal(
+                prefix
+                    .iter()
+                    .flat_map(|&byte| [byte >> 4, byte & 0x0f])
+                    .map(Some),
+            ),
+            LengthEncoding::Ebcdic => decimal(prefix.iter().map(|&byte| byte.checked_sub(0xf0))),
+        }
+        .ok_or_else(invalid_prefix)?;
+
+        let value = usize::try_from(value).map_err(|_| invalid_prefix())?;
+
+        if self.inclusive {
+            value.checked_sub(self.width).ok_or_else(invalid_prefix)
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Writes the prefix for a message of `message_size` bytes.
+    pub fn write(&self, message_size: usize, dst: &mut BytesMut) -> Result<(), FramingError> {
+        let value = if self.inclusive {
+            message_size + self.width
+        } else {
+            message_size
+        };
+
+        if value as u64 > self.max_value() {
+            return Err(FramingError::Oversize {
+                length: message_size,
+                max: self.max_value() as usize,
+            });
+        }
+
+        let mut prefix = vec![0; self.width];
+        let mut remaining = value as u64;
+
+        match self.encoding {
+            LengthEncoding::BinaryBigEndian | LengthEncoding::BinaryLittleEndian => {
+                for byte in prefix.iter_mut().rev() {
+                    *byte = (remaining % 256) as u8;
+                    remaining /= 256;
+                }
+                if self.encoding == LengthEncoding::BinaryLittleEndian {
+                    prefix.reverse();
+                }
+            }
+            LengthEncoding::Ascii | LengthEncoding::Ebcdic => {
+                let zero = if self.encoding == LengthEncoding::Ascii {
+                    b'0'
+                } else {
+                    0xf0
+                };
+                for byte in prefix.iter_mut().rev() {
+                    *byte = zero + (remaining % 10) as u8;
+                    remaining /= 10;
+                }
+            }
+            LengthEncoding::Bcd => {
+                for byte in prefix.iter_mut().rev() {
+                    *byte = (remaining % 10) as u8;
+                    remaining /= 10;
+                    *byte |= ((remaining % 10) as u8) << 4;
+                    remaining /= 10;
+                }
+            }
+        }
+
+        dst.put_slice(&prefix);
+
+        Ok(())
+    }
+
+    /// Largest message the prefix can announce.
+    pub fn max_message_size(&self) -> u64 {
+        if self.inclusive {
+            self.max_value().saturating_sub(self.width as u64)
+        } else {
+            self.max_value()
+        }
+    }
+
+    fn max_value(&self) -> u64 {
+        let digits = match self.encoding {
+            LengthEncoding::BinaryBigEndian | LengthEncoding::BinaryLittleEndian => {
+                return 256u64
+                    .checked_pow(self.width as u32)
+                    .map_or(u64::MAX, |limit| limit - 1);
+            }
+            LengthEncoding::Ascii | LengthEncoding::Ebcdic => self.width,
+            LengthEncoding::Bcd => self.width * 2,
+        };
+
+        10u64
+            .checked_pow(digits as u32)
+            .map_or(u64::MAX, |limit| limit - 1)
+    }
+}
+
+impl Default for LengthPrefix {
+    /// Two byte, big-endian binary length that does not count itself.
+    fn default() -> Self {
+        Self::new(2, LengthEncoding::BinaryBigEndian)
+    }
+}
+
+fn decimal(digits: impl Iterator<Item = Option<u8>>) -> Option<u64> {
+    digits
+        .into_iter()
+        .try_fold(0u64, |value, digit| match digit {
+            Some(digit) if digit <= 9 => value.checked_mul(10)?.checked_add(digit as u64),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::{LengthEncoding, LengthPrefix};
+    use crate::message_machine::FramingError;
+
+    fn write(length_prefix: LengthPrefix, message_size: usize) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        length_prefix.write(message_size, &mut dst).unwrap();
+
+        dst.to_vec()
+    }
+
+    mod get_message_length {
+        use crate::length_prefix::LengthPrefix;
+        use crate::test_support::get_buffer_from_file;
+
+        #[test]
+        fn should_get_proper_length_from_authorization_advise() {
+            let buffer =
+                get_buffer_from_file("sample_messages/i2c-authorization-advice-request.bin");
+
+            let results = LengthPrefix::default().read(&buffer);
+
+            assert!(results.is_ok());
+            assert_eq!(results.unwrap(), 506);
+        }
+
+        #[test]
+        fn should_get_proper_length_from_authorization_request() {
+            let buffer =
+                get_buffer_from_file("sample_messages/i2c-authorization-mastercard-request.bin");
+
+            let results = LengthPrefix::default().read(&buffer);
+
+            assert!(results.is_ok());
+            assert_eq!(results.unwrap(), 1540);
+        }
+    }
+
+    #[test]
+    fn should_return_short_prefix_when_not_enough_bytes() {
+        let results = LengthPrefix::new(4, LengthEncoding::Ascii).read(b"050");
+
+        assert!(matches!(
+            results,
+            Err(FramingError::ShortPrefix {
+                expected: 4,
+                available: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn should_read_and_write_every_encoding() {
+        let cases: [(LengthPrefix, &[u8]); 6] = [
+            (
+                LengthPrefix::new(2, LengthEncoding::BinaryBigEndian),
+                &[0x01, 0xfa],
+            ),
+            (
+                LengthPrefix::new(2, LengthEncoding::BinaryLittleEndian),
+                &[0xfa, 0x01],
+            ),
+            (
+                LengthPrefix::new(4, LengthEncoding::BinaryBigEndian),
+                &[0, 0, 0x01, 0xfa],
+            ),
+            (LengthPrefix::new(4, LengthEncoding::Ascii), b"0506"),
+            (LengthPrefix::new(2, LengthEncoding::Bcd), &[0x05, 0x06]),
+            (
+                LengthPrefix::new(4, LengthEncoding::Ebcdic),
+                &[0xf0, 0xf5, 0xf0, 0xf6],
+            ),
+        ];
+
+        for (length_prefix, prefix) in cases {
+            assert_eq!(
+                length_prefix.read(prefix).unwrap(),
+                506,
+                "{:?}",
+                length_prefix
+            );
+            assert_eq!(write(length_prefix, 506), prefix, "{:?}", length_prefix);
+        }
+    }
+
+    #[test]
+    fn should_count_prefix_when_inclusive() {
+        let length_prefix = LengthPrefix::new(4, LengthEncoding::Ascii).inclusive();
+
+        assert_eq!(length_prefix.read(b"0510").unwrap(), 506);
+        assert_eq!(write(length_prefix, 506), b"0510");
+    }
+
+    #[test]
+    fn should_reject_non_digit_prefix() {
+        let results = LengthPrefix::new(4, LengthEncoding::Ascii).read(b"05x6");
+
+        assert!(matches!(results, Err(FramingError::InvalidPrefix { .. })));
+    }
+
+    #[test]
+    fn should_refuse_to_write_length_that_does_not_fit() {
+        let mut dst = BytesMut::new();
+
+        let results = LengthPrefix::new(2, LengthEncoding::Ascii).write(100, &mut dst);
+
+        assert!(matches!(results, Err(FramingError::Oversize { .. })));
+    }
+}
@@ -0,0 +1,32 @@
+pub mod capture;
+pub mod client;
+pub mod config;
+mod connection_writer;
+pub mod correlator;
+pub mod duplicates;
+pub mod fields;
+pub mod journal;
+pub mod latency;
+pub mod ledger;
+pub mod length_prefix;
+pub mod message_handler;
+pub mod message_header;
+mod message_helpers;
+pub mod message_machine;
+pub mod network;
+pub mod pretty;
+pub mod proxy;
+pub mod replay;
+pub mod reversal;
+pub mod rules;
+mod server;
+pub mod store_forward;
+#[cfg(test)]
+pub(crate) mod test_support;
+
+pub use config::Config;
+pub use message_handler::{Approve, MessageHandler, Router};
+pub use message_machine::{FrameWriter, FramingError, IsoFrame, IsoFrameCodec, StateMachine};
+pub use server::{Server, ServerBuilder};
+
+pub const MAX_MESSAGE_SIZE: usize = 3_418;
@@ -1,108 +1,70 @@
-use iso_8583_message::IsoMessage;
-use message_machine::StateMachine;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{error::Error, process};
 
-use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt, WriteHalf},
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
-    time::sleep,
-};
+use clap::Parser;
+use futures::future::try_join_all;
+use socketron::config::LogFormat;
+use tracing::info;
 
-mod message_helpers;
-mod message_machine;
-
-const SOCKET_PORT: u16 = 8006;
-pub const LENGTH_PREFIX_SIZE: usize = 2;
+mod cli;
+mod commands;
 
 #[tokio::main]
-async fn main() -> Result<(), io::Error> {
-    let addr = SocketAddr::from(([127, 0, 0, 1], SOCKET_PORT));
-    let listener = TcpListener::bind(addr).await?;
-
-    println!("TcpServer started up on {}", addr);
-
-    while let Ok((stream, connection_addr)) = listener.accept().await {
-        println!("Connection made on {}", connection_addr);
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = cli::Args::parse();
+    let config = match args.config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
 
-        tokio::spawn(async move {
-            match handle_connection(stream).await {
-                Ok(_) => {
-                    println!("Successfully handled connection on {}", connection_addr)
-                }
-                Err(e) => {
-                    println!(
-                        "An {} error occurred handling connection on {}. Dropping connection",
-                        e, connection_addr
-                    );
-                }
-            };
-        });
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
     }
-    Ok(())
-}
 
-async fn handle_connection(stream: TcpStream) -> Result<(), io::Error> {
-    let (mut reader, writer) = tokio::io::split(stream);
-    let writer = Arc::new(Mutex::new(writer));
+    let subscriber =
+        tracing_subscriber::fmt().with_max_level(tracing::Level::from(config.log_level));
 
-    let mut state_machine = StateMachine::new();
-    let mut temp_buf = [0; 4096];
-
-    loop {
-        let received_messages = match reader.read(&mut temp_buf).await {
-            Ok(0) => {
-                println!("Received 0 bytes breaking");
-                break;
-            }
-            Ok(bytes_read) => {
-                // println!("Received {} bytes", bytes_read);
-                // println!("StateMachine: {:?}", state_machine);
-                state_machine.process(&temp_buf[..bytes_read])
-            }
-
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
-            }
-            Err(e) => {
-                return Err(e.into());
-            }
-        };
-        // println!("StateMachine After: {:?}", state_machine);
-
-        // println!("ReceivedMessage: {:?}", received_messages);
+    match config.log_format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_span_list(true).init(),
+    }
 
-        if let Some(messages) = received_messages {
-            for message in messages {
-                let socket_writer = writer.clone();
-                tokio::spawn(async move {
-                    handle_message(message, socket_writer).await;
-                });
-            }
+    match &args.command {
+        Some(cli::Command::Client(client_args)) => {
+            return commands::client::run(&config, client_args).await
+        }
+        Some(cli::Command::Proxy(proxy_args)) => {
+            return commands::proxy::run(&config, proxy_args).await
         }
+        Some(cli::Command::Replay(replay_args)) => {
+            return commands::replay::run(&config, replay_args).await
+        }
+        Some(cli::Command::Encode(convert_args)) => {
+            return commands::convert::encode(&config, convert_args)
+        }
+        Some(cli::Command::Decode(convert_args)) => {
+            return commands::convert::decode(&config, convert_args)
+        }
+        Some(cli::Command::Inspect(inspect_args)) => {
+            return commands::inspect::run(&config, inspect_args)
+        }
+        None => {}
     }
 
-    Ok(())
-}
-
-async fn handle_message(message: IsoMessage, socket_writer: Arc<Mutex<WriteHalf<TcpStream>>>) {
-    // Almost there
-    // Do something
-    println!("Handling message");
-    sleep(Duration::from_secs(2)).await;
-
-    let response_message = message
-        .to_response("00")
-        .unwrap()
-        .get_message_buffer()
-        .unwrap();
+    // One handler for every listener, so balances and the journal are the same on all of them
+    let builder = config.server_builder()?;
+    let mut servers = Vec::new();
 
-    let mut socket_writer = socket_writer.lock().await;
+    for addr in &config.listen {
+        let server = builder.clone().bind(addr).await?;
+        info!("TcpServer started up on {}", server.local_addr()?);
+        servers.push(server.run());
+    }
 
-    socket_writer
-        .write_u16_le(response_message.len() as u16)
-        .await
-        .unwrap();
+    try_join_all(servers).await?;
 
-    socket_writer.write(&response_message).await.unwrap();
+    Ok(())
 }
@@ -0,0 +1,136 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use iso_8583_message::IsoMessage;
+use tokio::time::sleep;
+use tracing::warn;
+
+/// Builds the response to a request. Implement this to plug authorization, reversal or
+/// network management logic into the server, and register it with a `Router`.
+#[async_trait]
+pub trait MessageHandler: Send + Sync {
+    /// The response to send back, or `None` if the request is not answered.
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage>;
+}
+
+#[async_trait]
+impl<H: MessageHandler + ?Sized> MessageHandler for Box<H> {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        (**self).handle(request).await
+    }
+}
+
+/// Dispatches each request to the handler registered for its MTI, or to the fallback.
+pub struct Router {
+    routes: HashMap<String, Box<dyn MessageHandler>>,
+    fallback: Box<dyn MessageHandler>,
+}
+
+impl Router {
+    pub fn new(fallback: impl MessageHandler + 'static) -> Self {
+        Self {
+            routes: HashMap::new(),
+            fallback: Box::new(fallback),
+        }
+    }
+
+    /// Sends requests with `mti` to `handler`, replacing any handler already registered for it.
+    pub fn route(mut self, mti: &str, handler: impl MessageHandler + 'static) -> Self {
+        self.routes.insert(mti.to_string(), Box::new(handler));
+        self
+    }
+
+    fn handler_for(&self, request: &IsoMessage) -> &dyn MessageHandler {
+        request
+            .get_field(0)
+            .and_then(|mti| self.routes.get(mti))
+            .unwrap_or(&self.fallback)
+            .as_ref()
+    }
+}
+
+#[async_trait]
+impl MessageHandler for Router {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        self.handler_for(request).handle(request).await
+    }
+}
+
+/// Approves every request with response code `00`, or the configured one, after `delay`.
+#[derive(Debug, Clone)]
+pub struct Approve {
+    delay: Duration,
+    response_code: String,
+}
+
+impl Approve {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            response_code: "00".to_string(),
+        }
+    }
+
+    pub fn response_code(mut self, response_code: &str) -> Self {
+        self.response_code = response_code.to_string();
+        self
+    }
+}
+
+#[async_trait]
+impl MessageHandler for Approve {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        sleep(self.delay).await;
+
+        match request.to_response(&self.response_code) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!("Unable to build response: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+    use iso_8583_message::IsoMessage;
+
+    use super::{MessageHandler, Router};
+    use crate::test_support::get_message_from_file;
+
+    struct Respond(&'static str);
+
+    #[async_trait]
+    impl MessageHandler for Respond {
+        async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+            request.to_response(self.0).ok()
+        }
+    }
+
+    fn router() -> Router {
+        Router::new(Respond("96"))
+            .route("0100", Respond("00"))
+            .route("0420", Respond("21"))
+    }
+
+    #[tokio::test]
+    async fn should_dispatch_by_mti() {
+        let request = get_message_from_file("sample_messages/i2c-reversal-request.bin");
+
+        let response = router().handle(&request).await.unwrap();
+
+        assert_eq!(response.get_field(0), Some("0430"));
+        assert_eq!(response.get_field(39), Some("21"));
+    }
+
+    #[tokio::test]
+    async fn should_use_fallback_for_unrouted_mti() {
+        let request = get_message_from_file("sample_messages/i2c-financial-request.bin");
+
+        let response = router().handle(&request).await.unwrap();
+
+        assert_eq!(response.get_field(39), Some("96"));
+    }
+}
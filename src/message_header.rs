@@ -0,0 +1,239 @@
+use std::{ops::Range, str::FromStr};
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+
+const TPDU_ID: u8 = 0x60;
+const VISA_HEADER_LENGTH: usize = 22;
+
+/// Layout of the header some links put between the length prefix and the MTI.
+///
+/// ```toml
+/// kind = "routing"
+/// length = 8
+/// destination = 0
+/// source = 4
+/// address_length = 4
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum HeaderFormat {
+    /// 5 byte TPDU: `0x60`, a 2 byte destination address and a 2 byte source address.
+    Tpdu,
+    /// 22 byte Visa base I header. Destination and source station ids are at bytes 5 and 8,
+    /// and bytes 3-4 hold the length of the header plus message.
+    Visa,
+    /// Fixed length vendor routing header with the destination and source addresses at the
+    /// given offsets.
+    Routing {
+        length: usize,
+        destination: usize,
+        source: usize,
+        address_length: usize,
+    },
+}
+
+impl HeaderFormat {
+    pub fn length(&self) -> usize {
+        match self {
+            HeaderFormat::Tpdu => 5,
+            HeaderFormat::Visa => VISA_HEADER_LENGTH,
+            HeaderFormat::Routing { length, .. } => *length,
+        }
+    }
+
+    /// Checks that both addresses of a routing header lie within it.
+    pub fn validate(&self) -> Result<(), String> {
+        let (destination, source) = self.addresses();
+
+        for (name, range) in [("destination", destination), ("source", source)] {
+            if range.is_empty() || range.end > self.length() {
+                return Err(format!(
+                    "{} address {:?} does not fit in a {} byte header",
+                    name,
+                    range,
+                    self.length()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Destination and source address ranges within the header.
+    fn addresses(&self) -> (Range<usize>, Range<usize>) {
+        match *self {
+            HeaderFormat::Tpdu => (1..3, 3..5),
+            HeaderFormat::Visa => (5..8, 8..11),
+            HeaderFormat::Routing {
+                destination,
+                source,
+                address_length,
+                ..
+            } => (
+                destination..destination.saturating_add(address_length),
+                source..source.saturating_add(address_length),
+            ),
+        }
+    }
+}
+
+impl FromStr for HeaderFormat {
+    type Err = String;
+
+    /// Parses `tpdu`, `visa` or `routing:LENGTH:DESTINATION:SOURCE:ADDRESS_LENGTH`, e.g.
+    /// `routing:8:0:4:4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<_>>()[..] {
+            ["tpdu"] => Ok(HeaderFormat::Tpdu),
+            ["visa"] => Ok(HeaderFormat::Visa),
+            ["routing", length, destination, source, address_length] => {
+                let number = |value: &str| {
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| format!("{:?} in routing header is not a number", value))
+                };
+
+                Ok(HeaderFormat::Routing {
+                    length: number(length)?,
+                    destination: number(destination)?,
+                    source: number(source)?,
+                    address_length: number(address_length)?,
+                })
+            }
+            _ => Err(format!(
+                "unknown header format {:?}, expected tpdu, visa or \
+                 routing:LENGTH:DESTINATION:SOURCE:ADDRESS_LENGTH",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageHeader {
+    format: HeaderFormat,
+    bytes: Vec<u8>,
+}
+
+impl MessageHeader {
+    /// Reads a header from the start of `buf`, or `None` if `buf` is too short to hold one.
+    pub fn parse(format: HeaderFormat, buf: &[u8]) -> Option<Self> {
+        let bytes = buf.get(..format.length())?.to_vec();
+
+        Some(Self { format, bytes })
+    }
+
+    /// A header with zeroed addresses for messages that are not replies to anything.
+    pub fn empty(format: HeaderFormat) -> Self {
+        let mut bytes = vec![0; format.length()];
+
+        match format {
+            HeaderFormat::Tpdu => bytes[0] = TPDU_ID,
+            HeaderFormat::Visa => {
+                bytes[0] = VISA_HEADER_LENGTH as u8;
+                bytes[1] = 0x01;
+                bytes[2] = 0x02;
+            }
+            HeaderFormat::Routing { .. } => {}
+        }
+
+        Self { format, bytes }
+    }
+
+    pub fn destination(&self) -> &[u8] {
+        &self.bytes[self.format.addresses().0]
+    }
+
+    pub fn source(&self) -> &[u8] {
+        &self.bytes[self.format.addresses().1]
+    }
+
+    /// The header to send back with a response: the same bytes with source and destination
+    /// swapped.
+    pub fn to_response(&self) -> Self {
+        let (destination, source) = self.format.addresses();
+        let mut bytes = self.bytes.clone();
+
+        bytes[destination.clone()].copy_from_slice(&self.bytes[source.clone()]);
+        bytes[source].copy_from_slice(&self.bytes[destination]);
+
+        Self {
+            format: self.format,
+            bytes,
+        }
+    }
+
+    /// Writes the header in front of a message of `message_size` bytes.
+    pub fn write(&self, message_size: usize, dst: &mut BytesMut) {
+        let start = dst.len();
+        dst.put_slice(&self.bytes);
+
+        if self.format == HeaderFormat::Visa {
+            let total_length = (self.bytes.len() + message_size) as u16;
+            dst[start + 3..start + 5].copy_from_slice(&total_length.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::{HeaderFormat, MessageHeader};
+
+    #[test]
+    fn should_reject_routing_addresses_outside_header() {
+        let format: HeaderFormat = "routing:8:0:6:4".parse().unwrap();
+
+        assert!(format.validate().is_err());
+        assert!("routing:8:0:4:4"
+            .parse::<HeaderFormat>()
+            .unwrap()
+            .validate()
+            .is_ok());
+        assert!("routing:8:0".parse::<HeaderFormat>().is_err());
+    }
+
+    #[test]
+    fn should_return_none_if_buffer_shorter_than_header() {
+        assert!(MessageHeader::parse(HeaderFormat::Tpdu, &[0x60, 0x00]).is_none());
+    }
+
+    #[test]
+    fn should_swap_tpdu_source_and_destination_for_response() {
+        let header = MessageHeader::parse(HeaderFormat::Tpdu, &[0x60, 0x00, 0x01, 0x00, 0x02])
+            .unwrap()
+            .to_response();
+
+        assert_eq!(header.destination(), &[0x00, 0x02]);
+        assert_eq!(header.source(), &[0x00, 0x01]);
+    }
+
+    #[test]
+    fn should_swap_routing_addresses_for_response() {
+        let format = HeaderFormat::Routing {
+            length: 8,
+            destination: 0,
+            source: 4,
+            address_length: 4,
+        };
+        let header = MessageHeader::parse(format, b"DESTSRCE0100").unwrap();
+        let mut dst = BytesMut::new();
+
+        header.to_response().write(4, &mut dst);
+
+        assert_eq!(&dst[..], b"SRCEDEST");
+    }
+
+    #[test]
+    fn should_write_total_length_into_visa_header() {
+        let header = MessageHeader::empty(HeaderFormat::Visa);
+        let mut dst = BytesMut::new();
+
+        header.write(100, &mut dst);
+
+        assert_eq!(dst.len(), 22);
+        assert_eq!(&dst[3..5], &122u16.to_be_bytes());
+    }
+}
@@ -1,220 +1,687 @@
+use std::{collections::VecDeque, error::Error, fmt, io::IoSlice};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
 use crate::{
-    message_helpers::{
-        get_message_length, received_full_message, received_multiple_messages,
-        received_new_message, received_partial_message, received_rest_of_message,
-    },
-    LENGTH_PREFIX_SIZE,
+    length_prefix::LengthPrefix,
+    message_header::{HeaderFormat, MessageHeader},
+    message_helpers::received_new_message,
+    MAX_MESSAGE_SIZE,
 };
 
 use iso_8583_message::IsoMessage;
 
 #[derive(Debug)]
-pub enum State {
-    Ready,
-    Waiting,
-    Delivering,
-}
-
-#[derive(Debug)]
-
-struct InnerContext {
-    buffer: Vec<u8>,
-    waiting_for_bytes: usize,
-    messages: Vec<IsoMessage>,
+pub enum FramingError {
+    /// Fewer bytes than the length prefix were available.
+    ShortPrefix {
+        expected: usize,
+        available: usize,
+    },
+    /// The length prefix could not be decoded with the configured encoding.
+    /// The stream cannot be resynchronised after this.
+    InvalidPrefix {
+        prefix: Vec<u8>,
+    },
+    /// The length prefix announced a message larger than the codec allows.
+    /// The stream cannot be resynchronised after this.
+    Oversize {
+        length: usize,
+        max: usize,
+    },
+    /// A complete frame was received but could not be parsed as an ISO 8583 message.
+    /// `raw` holds the bytes after the header, if there was one.
+    Parse {
+        header: Option<MessageHeader>,
+        raw: Vec<u8>,
+        reason: String,
+    },
+    /// A new message started while a partial one was still buffered. The partial
+    /// message was discarded.
+    Desync {
+        discarded: usize,
+    },
+    /// An outgoing message could not be serialised.
+    Encode {
+        reason: String,
+    },
+    Io(io::Error),
 }
 
-impl InnerContext {
-    fn reset(&mut self) {
-        self.buffer.clear();
-        self.waiting_for_bytes = 0;
-        // Do not clear messages
-    }
-    fn get_messages_from_buffer(&mut self, bytes: &[u8]) {
-        let mut received_buf = bytes;
-
-        while received_buf.len() > 0 {
-            if self.buffer.len() > 0 {
-                let partial_message_size = get_message_length(&self.buffer)
-                    .expect("Unable to get message length")
-                    as usize;
-                let partial_received_buf_size = received_buf.len();
-                let partial_remaining_buf_size =
-                    partial_message_size - (partial_received_buf_size + LENGTH_PREFIX_SIZE);
-
-                self.buffer
-                    .append(&mut received_buf[..partial_remaining_buf_size].to_vec());
-
-                received_buf = &received_buf[partial_received_buf_size..];
-
-                if partial_message_size == self.buffer.len() - LENGTH_PREFIX_SIZE {
-                    self.messages.push(
-                        IsoMessage::from_buffer(std::mem::take(
-                            &mut self.buffer[LENGTH_PREFIX_SIZE..].to_vec(),
-                        ))
-                        .unwrap(),
-                    );
-                    self.reset();
-                }
-            } else {
-                let message_size = get_message_length(received_buf)
-                    .expect("Unable to get message length")
-                    as usize;
-                let message_size_with_length_header = message_size + LENGTH_PREFIX_SIZE;
-                let received_buf_size = received_buf.len();
-
-                if message_size_with_length_header == received_buf_size {
-                    self.messages.push(
-                        IsoMessage::from_buffer(received_buf[LENGTH_PREFIX_SIZE..].to_vec())
-                            .unwrap(),
-                    );
-                    received_buf = &received_buf[0..0];
-                } else if message_size_with_length_header < received_buf_size {
-                    self.messages.push(
-                        IsoMessage::from_buffer(
-                            received_buf[LENGTH_PREFIX_SIZE..message_size_with_length_header]
-                                .to_vec(),
-                        )
-                        .unwrap(),
-                    );
-                    received_buf = &received_buf[message_size_with_length_header..];
-                } else if message_size_with_length_header > received_buf_size {
-                    self.buffer.append(&mut received_buf.to_vec());
-                    received_buf = &received_buf[0..0];
-                }
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FramingError::ShortPrefix {
+                expected,
+                available,
+            } => write!(
+                f,
+                "expected {} length prefix bytes but only {} available",
+                expected, available
+            ),
+            FramingError::InvalidPrefix { prefix } => {
+                write!(f, "invalid length prefix {:02x?}", prefix)
             }
+            FramingError::Oversize { length, max } => {
+                write!(f, "message length {} exceeds maximum of {}", length, max)
+            }
+            FramingError::Parse { raw, reason, .. } => {
+                write!(f, "unable to parse {} byte message: {}", raw.len(), reason)
+            }
+            FramingError::Desync { discarded } => write!(
+                f,
+                "new message started before previous one completed, discarded {} bytes",
+                discarded
+            ),
+            FramingError::Encode { reason } => write!(f, "unable to encode message: {}", reason),
+            FramingError::Io(e) => write!(f, "{}", e),
         }
     }
 }
 
+impl Error for FramingError {}
+
+impl From<io::Error> for FramingError {
+    fn from(e: io::Error) -> Self {
+        FramingError::Io(e)
+    }
+}
+
+/// A message along with the header that came before it on links that use one.
 #[derive(Debug)]
-pub struct StateMachine<State> {
-    inner_context: InnerContext,
-    inner_state: State,
+pub struct IsoFrame {
+    pub header: Option<MessageHeader>,
+    pub message: IsoMessage,
 }
 
-impl StateMachine<State> {
-    pub fn new() -> Self {
-        let inner_context = InnerContext {
-            buffer: Vec::with_capacity(4096),
-            messages: Vec::new(),
-            waiting_for_bytes: 0,
-        };
+impl From<IsoMessage> for IsoFrame {
+    fn from(message: IsoMessage) -> Self {
+        Self {
+            header: None,
+            message,
+        }
+    }
+}
 
+/// Length-prefixed ISO 8583 framing for use with `Framed`, `FramedRead` and `FrameWriter`.
+#[derive(Debug, Clone)]
+pub struct IsoFrameCodec {
+    length_prefix: LengthPrefix,
+    header_format: Option<HeaderFormat>,
+    max_message_size: usize,
+}
+
+impl IsoFrameCodec {
+    pub fn new(length_prefix: LengthPrefix, header_format: Option<HeaderFormat>) -> Self {
         Self {
-            inner_context,
-            inner_state: State::Ready,
+            length_prefix,
+            header_format,
+            max_message_size: MAX_MESSAGE_SIZE,
         }
     }
 
-    pub fn process(&mut self, bytes: &[u8]) -> Option<Vec<IsoMessage>> {
-        match self {
-            StateMachine {
-                inner_state: State::Ready,
-                ..
-            } => self.process_ready(bytes),
-            StateMachine {
-                inner_state: State::Waiting,
-                ..
-            } => self.process_waiting(bytes),
-            _ => panic!("Unknown StateMachine state at this time"),
+    /// Rejects frames longer than `max_message_size` instead of `MAX_MESSAGE_SIZE`.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    fn header_size(&self) -> usize {
+        self.header_format.map_or(0, |format| format.length())
+    }
+
+    /// Splits the next whole frame, length prefix included, off the front of `src` without
+    /// looking inside it, or returns `None` until all of it has arrived.
+    fn split_frame(&self, src: &mut BytesMut) -> Result<Option<BytesMut>, FramingError> {
+        let prefix_size = self.length_prefix.width();
+
+        if src.len() < prefix_size {
+            return Ok(None);
+        }
+
+        let message_size = match self.length_prefix.read(src) {
+            Ok(message_size) => message_size,
+            Err(error) => {
+                // There is no way of knowing where the next message starts
+                src.clear();
+                return Err(error);
+            }
         };
 
-        if let StateMachine {
-            inner_state: State::Delivering,
-            ..
-        } = self
-        {
-            // println!("Getting Messages");
-            return self.process_delivering();
+        if message_size > self.max_message_size {
+            // There is no way of knowing where the next message starts
+            src.clear();
+            return Err(FramingError::Oversize {
+                length: message_size,
+                max: self.max_message_size,
+            });
+        }
+
+        let message_size_with_length_header = message_size + prefix_size;
+
+        if src.len() < message_size_with_length_header {
+            src.reserve(message_size_with_length_header - src.len());
+            return Ok(None);
         }
 
-        None
+        Ok(Some(src.split_to(message_size_with_length_header)))
     }
+}
+
+impl Default for IsoFrameCodec {
+    fn default() -> Self {
+        Self::new(LengthPrefix::default(), None)
+    }
+}
+
+impl Decoder for IsoFrameCodec {
+    type Item = IsoFrame;
+    type Error = FramingError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<IsoFrame>, FramingError> {
+        let mut body = match self.split_frame(src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        body.advance(self.length_prefix.width());
+
+        let header = match self.header_format {
+            Some(format) => match MessageHeader::parse(format, &body) {
+                Some(header) => {
+                    body.advance(format.length());
+                    Some(header)
+                }
+                None => {
+                    return Err(FramingError::Parse {
+                        header: None,
+                        raw: body.to_vec(),
+                        reason: "frame is shorter than its header".to_string(),
+                    });
+                }
+            },
+            None => None,
+        };
 
-    fn process_ready(&mut self, bytes: &[u8]) {
-        // println!("Processing Ready: {:?}", bytes);
-        if received_full_message(bytes) {
-            // println!("Processing Full Message: {:?}", bytes);
-            self.inner_state = State::Delivering;
-            self.inner_context.get_messages_from_buffer(bytes);
+        let raw = body.to_vec();
 
-            return;
+        match IsoMessage::from_buffer(raw.clone()) {
+            Ok(message) => Ok(Some(IsoFrame { header, message })),
+            Err(error) => Err(FramingError::Parse {
+                header,
+                raw,
+                reason: format!("{:?}", error),
+            }),
         }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<IsoFrame>, FramingError> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None if buf.len() < self.length_prefix.width() => {
+                let available = buf.len();
+                buf.clear();
+                Err(FramingError::ShortPrefix {
+                    expected: self.length_prefix.width(),
+                    available,
+                })
+            }
+            None => {
+                buf.clear();
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed mid-message",
+                )
+                .into())
+            }
+        }
+    }
+}
+
+impl Encoder<IsoFrame> for IsoFrameCodec {
+    type Error = FramingError;
+
+    fn encode(&mut self, item: IsoFrame, dst: &mut BytesMut) -> Result<(), FramingError> {
+        let message_buffer =
+            item.message
+                .get_message_buffer()
+                .map_err(|error| FramingError::Encode {
+                    reason: format!("{:?}", error),
+                })?;
+
+        let frame_size = self.header_size() + message_buffer.len();
 
-        if received_partial_message(&self.inner_context.buffer, bytes) {
-            // println!("Processing Partial Message: {:?}", bytes);
+        if frame_size > self.max_message_size {
+            return Err(FramingError::Oversize {
+                length: frame_size,
+                max: self.max_message_size,
+            });
+        }
 
-            self.inner_state = State::Waiting;
-            self.inner_context.get_messages_from_buffer(bytes);
+        dst.reserve(self.length_prefix.width() + frame_size);
+        self.length_prefix.write(frame_size, dst)?;
 
-            return;
+        // The link's header format decides whether a header is written, not the frame
+        if let Some(format) = self.header_format {
+            let header = item.header.unwrap_or_else(|| MessageHeader::empty(format));
+            header.write(message_buffer.len(), dst);
         }
 
-        if received_multiple_messages(bytes) {
-            // println!("Processing Multiple Message: {:?}", bytes);
+        dst.put_slice(&message_buffer);
+
+        Ok(())
+    }
+}
 
-            self.inner_state = State::Delivering;
-            self.inner_context.get_messages_from_buffer(bytes);
+/// Splits a stream into frames on the length prefix alone, leaving each frame's bytes, prefix
+/// and header included, exactly as they arrived. For relaying traffic that need not parse.
+#[derive(Debug, Clone)]
+pub struct RawFrameCodec {
+    codec: IsoFrameCodec,
+}
 
-            return;
+impl RawFrameCodec {
+    /// Frames as `codec` does, with its length prefix and maximum frame size.
+    pub fn new(codec: IsoFrameCodec) -> Self {
+        Self { codec }
+    }
+}
+
+impl Decoder for RawFrameCodec {
+    type Item = BytesMut;
+    type Error = FramingError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, FramingError> {
+        self.codec.split_frame(src)
+    }
+}
+
+/// Writes messages framed by the same `IsoFrameCodec` the reading side decodes with, so
+/// responses always use the request's length prefix. Queued frames are written together with
+/// vectored writes on `flush`.
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    writer: W,
+    codec: IsoFrameCodec,
+    buffer: BytesMut,
+    pending: VecDeque<Bytes>,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(writer: W, codec: IsoFrameCodec) -> Self {
+        Self {
+            writer,
+            codec,
+            buffer: BytesMut::with_capacity(4096),
+            pending: VecDeque::new(),
         }
+    }
+
+    /// Encodes `frame` for the next `flush`. A frame that cannot be encoded is not queued.
+    pub fn queue(&mut self, frame: IsoFrame) -> Result<(), FramingError> {
+        self.buffer.clear();
+        self.codec.encode(frame, &mut self.buffer)?;
+        self.pending.push_back(self.buffer.split().freeze());
 
-        panic!("Not handling load properly. TODO");
+        Ok(())
     }
 
-    fn process_waiting(&mut self, bytes: &[u8]) {
-        if received_new_message(bytes) {
-            self.inner_state = State::Ready;
-            self.inner_context.reset();
+    /// Writes every queued frame.
+    pub async fn flush(&mut self) -> Result<(), FramingError> {
+        while !self.pending.is_empty() {
+            let slices: Vec<IoSlice<'_>> = self
+                .pending
+                .iter()
+                .map(|frame| IoSlice::new(frame))
+                .collect();
+            let written = self.writer.write_vectored(&slices).await?;
+
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
 
-            return;
+            self.consume(written);
         }
 
-        if received_partial_message(&self.inner_context.buffer, bytes) {
-            self.inner_state = State::Waiting;
-            self.inner_context.get_messages_from_buffer(bytes);
+        self.writer.flush().await?;
 
-            return;
+        Ok(())
+    }
+
+    /// Drops the first `written` bytes of the queued frames.
+    fn consume(&mut self, mut written: usize) {
+        while let Some(frame) = self.pending.front_mut() {
+            if written < frame.len() {
+                frame.advance(written);
+                return;
+            }
+
+            written -= frame.len();
+            self.pending.pop_front();
         }
+    }
 
-        if received_rest_of_message(self.inner_context.waiting_for_bytes, bytes) {
-            self.inner_state = State::Delivering;
-            self.inner_context.get_messages_from_buffer(bytes);
+    #[cfg(test)]
+    fn into_inner(self) -> W {
+        self.writer
+    }
+}
 
-            return;
+/// Decodes messages from raw reads for callers that do not have an `AsyncRead` to wrap in
+/// `FramedRead`.
+#[derive(Debug)]
+pub struct StateMachine {
+    codec: IsoFrameCodec,
+    buffer: BytesMut,
+    frames: Vec<IsoFrame>,
+}
+
+impl StateMachine {
+    pub fn new(codec: IsoFrameCodec) -> Self {
+        Self {
+            codec,
+            buffer: BytesMut::with_capacity(4096),
+            frames: Vec::new(),
         }
+    }
 
-        if received_multiple_messages(bytes) {
-            self.inner_state = State::Delivering;
-            self.inner_context.get_messages_from_buffer(bytes);
+    /// Feeds received bytes through the machine, returning any messages that are now complete.
+    ///
+    /// A single read can contain several frames, so an error does not discard the rest of the
+    /// bytes. After an `Err` call `process` again with an empty slice to collect the remaining
+    /// errors and messages; it returns `Ok` once nothing else is pending.
+    pub fn process(&mut self, bytes: &[u8]) -> Result<Option<Vec<IsoFrame>>, FramingError> {
+        if !self.buffer.is_empty()
+            && received_new_message(&self.codec.length_prefix, self.codec.header_size(), bytes)
+        {
+            let discarded = self.buffer.len();
+            self.buffer.clear();
+            self.buffer.extend_from_slice(bytes);
 
-            return;
+            return Err(FramingError::Desync { discarded });
         }
 
-        panic!("Not handling load properly. TODO");
-    }
+        self.buffer.extend_from_slice(bytes);
 
-    fn process_delivering(&mut self) -> Option<Vec<IsoMessage>> {
-        // println!("Processing Delivering: {:?}", self);
-        let iso_messages = std::mem::take(&mut self.inner_context.messages);
-        self.inner_context.messages.clear();
+        while let Some(frame) = self.codec.decode(&mut self.buffer)? {
+            self.frames.push(frame);
+        }
 
-        if self.inner_context.buffer.is_empty() {
-            self.inner_state = State::Ready;
-            return Some(iso_messages);
+        if self.frames.is_empty() {
+            Ok(None)
         } else {
-            self.inner_state = State::Waiting;
-            return Some(iso_messages);
+            Ok(Some(std::mem::take(&mut self.frames)))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::StateMachine;
+    use std::{
+        ffi::OsStr,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use bytes::BytesMut;
+    use tokio::io::AsyncWrite;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{FrameWriter, FramingError, IsoFrameCodec, StateMachine};
+    use crate::{
+        length_prefix::{LengthEncoding, LengthPrefix},
+        message_header::HeaderFormat,
+        test_support::get_buffer_from_file,
+    };
 
     #[test]
     fn it_works() {
-        let _state_machine = StateMachine::new();
+        let _state_machine = StateMachine::new(IsoFrameCodec::default());
+    }
+
+    #[test]
+    fn should_return_message_split_across_reads() {
+        let mut state_machine = StateMachine::new(IsoFrameCodec::default());
+        let buffer = get_buffer_from_file("sample_messages/i2c-authorization-advice-request.bin");
+
+        assert!(state_machine.process(&buffer[..1]).unwrap().is_none());
+        assert!(state_machine.process(&buffer[1..100]).unwrap().is_none());
+        let messages = state_machine.process(&buffer[100..]).unwrap().unwrap();
+
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn should_return_multiple_messages_from_one_read() {
+        let mut state_machine = StateMachine::new(IsoFrameCodec::default());
+        let mut buffer =
+            get_buffer_from_file("sample_messages/i2c-authorization-advice-request.bin");
+        let mut buffer_2 = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+        buffer.append(&mut buffer_2);
+
+        let messages = state_machine.process(&buffer).unwrap().unwrap();
+
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn should_keep_raw_bytes_on_parse_error_and_continue() {
+        let mut state_machine = StateMachine::new(IsoFrameCodec::default());
+        let mut buffer = vec![0x00, 0x04, b'j', b'u', b'n', b'k'];
+        let mut buffer_2 = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+        buffer.append(&mut buffer_2);
+
+        match state_machine.process(&buffer) {
+            Err(FramingError::Parse { raw, .. }) => assert_eq!(raw, b"junk"),
+            other => panic!("Expected parse error, got {:?}", other),
+        }
+
+        let messages = state_machine.process(&[]).unwrap().unwrap();
+
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn should_return_oversize_error_for_huge_length() {
+        let mut state_machine = StateMachine::new(IsoFrameCodec::default());
+
+        let results = state_machine.process(&[0xff, 0xff, b'0', b'1']);
+
+        assert!(matches!(results, Err(FramingError::Oversize { .. })));
+        assert!(state_machine.process(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_return_desync_when_new_message_interrupts_partial_one() {
+        let mut state_machine = StateMachine::new(IsoFrameCodec::default());
+        let buffer = get_buffer_from_file("sample_messages/i2c-authorization-advice-request.bin");
+        let buffer_2 = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+
+        assert!(state_machine.process(&buffer[..100]).unwrap().is_none());
+        let results = state_machine.process(&buffer_2);
+
+        assert!(matches!(
+            results,
+            Err(FramingError::Desync { discarded: 100 })
+        ));
+        let messages = state_machine.process(&[]).unwrap().unwrap();
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn codec_should_wait_for_rest_of_message() {
+        let mut codec = IsoFrameCodec::default();
+        let buffer = get_buffer_from_file("sample_messages/i2c-authorization-advice-request.bin");
+        let mut src = BytesMut::from(&buffer[..1]);
+
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&buffer[1..buffer.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&buffer[buffer.len() - 1..]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn codec_should_decode_multiple_messages_from_one_buffer() {
+        let mut codec = IsoFrameCodec::default();
+        let mut buffer =
+            get_buffer_from_file("sample_messages/i2c-authorization-advice-request.bin");
+        let mut buffer_2 = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+        buffer.append(&mut buffer_2);
+        let mut src = BytesMut::from(&buffer[..]);
+
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn codec_should_consume_unparsable_frame() {
+        let mut codec = IsoFrameCodec::default();
+        let mut buffer = vec![0x00, 0x04, b'j', b'u', b'n', b'k'];
+        let mut buffer_2 = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+        buffer.append(&mut buffer_2);
+        let mut src = BytesMut::from(&buffer[..]);
+
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(FramingError::Parse { .. })
+        ));
+        assert!(codec.decode(&mut src).unwrap().is_some());
+    }
+
+    #[test]
+    fn codec_should_return_short_prefix_at_eof() {
+        let mut codec = IsoFrameCodec::default();
+        let mut src = BytesMut::from(&[0x01][..]);
+
+        assert!(matches!(
+            codec.decode_eof(&mut src),
+            Err(FramingError::ShortPrefix {
+                expected: 2,
+                available: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn codec_should_encode_with_big_endian_length_prefix() {
+        let mut codec = IsoFrameCodec::default();
+        let buffer = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+        let frame = codec
+            .decode(&mut BytesMut::from(&buffer[..]))
+            .unwrap()
+            .unwrap();
+        let mut dst = BytesMut::new();
+
+        codec.encode(frame, &mut dst).unwrap();
+
+        assert_eq!(&dst[..], &buffer[..]);
+    }
+
+    #[test]
+    fn codec_should_use_configured_length_prefix() {
+        let length_prefix = LengthPrefix::new(4, LengthEncoding::Ascii).inclusive();
+        let mut codec = IsoFrameCodec::new(length_prefix, None);
+        let buffer = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+        let mut src = BytesMut::from(&b"0055"[..]);
+        src.extend_from_slice(&buffer[2..]);
+
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        let mut dst = BytesMut::new();
+        codec.encode(frame, &mut dst).unwrap();
+
+        assert_eq!(&dst[..4], b"0055");
+        assert_eq!(&dst[4..], &buffer[2..]);
+    }
+
+    #[tokio::test]
+    async fn should_round_trip_every_sample_message() {
+        for entry in std::fs::read_dir("sample_messages").unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.extension() != Some(OsStr::new("bin")) {
+                continue;
+            }
+
+            let buffer = get_buffer_from_file(path.to_str().unwrap());
+            let mut codec = IsoFrameCodec::default();
+            let frame = codec
+                .decode(&mut BytesMut::from(&buffer[..]))
+                .unwrap()
+                .unwrap();
+            let mut frame_writer = FrameWriter::new(Vec::new(), codec);
+
+            frame_writer.queue(frame).unwrap();
+            frame_writer.flush().await.unwrap();
+
+            assert_eq!(frame_writer.into_inner(), buffer, "{:?}", path);
+        }
+    }
+
+    /// Accepts at most a few bytes per write, like a socket with a full send buffer.
+    struct SlowWriter(Vec<u8>);
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let written = buf.len().min(7);
+            self.0.extend_from_slice(&buf[..written]);
+
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_writer_should_finish_partial_writes_of_queued_frames() {
+        let advice = get_buffer_from_file("sample_messages/i2c-authorization-advice-request.bin");
+        let request =
+            get_buffer_from_file("sample_messages/i2c-authorization-mastercard-request.bin");
+        let mut codec = IsoFrameCodec::default();
+        let mut frame_writer = FrameWriter::new(SlowWriter(Vec::new()), codec.clone());
+
+        for buffer in [&advice, &request] {
+            let frame = codec
+                .decode(&mut BytesMut::from(&buffer[..]))
+                .unwrap()
+                .unwrap();
+            frame_writer.queue(frame).unwrap();
+        }
+        frame_writer.flush().await.unwrap();
+
+        assert_eq!(frame_writer.into_inner().0, [advice, request].concat());
+    }
+
+    #[test]
+    fn codec_should_split_header_from_message() {
+        let mut codec = IsoFrameCodec::new(LengthPrefix::default(), Some(HeaderFormat::Tpdu));
+        let buffer = get_buffer_from_file("sample_messages/i2c-network-request.bin");
+        let mut src = BytesMut::from(&[0x00, 0x38, 0x60, 0x00, 0x01, 0x00, 0x02][..]);
+        src.extend_from_slice(&buffer[2..]);
+
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+
+        assert_eq!(frame.header.as_ref().unwrap().destination(), &[0x00, 0x01]);
+        assert_eq!(frame.header.as_ref().unwrap().source(), &[0x00, 0x02]);
+
+        let mut dst = BytesMut::new();
+        codec.encode(frame, &mut dst).unwrap();
+
+        assert_eq!(&dst[..7], &[0x00, 0x38, 0x60, 0x00, 0x01, 0x00, 0x02]);
+        assert_eq!(&dst[7..], &buffer[2..]);
     }
 }
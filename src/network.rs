@@ -0,0 +1,298 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use iso_8583_message::IsoMessage;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Response code for financial requests from a peer that has not signed on.
+const NOT_SIGNED_ON: &str = "91";
+
+/// Network management information codes in field 70 of an 0800.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkCode {
+    SignOn,
+    SignOff,
+    KeyChange,
+    Echo,
+}
+
+impl NetworkCode {
+    pub fn from_field(code: &str) -> Option<Self> {
+        match code {
+            "001" => Some(NetworkCode::SignOn),
+            "002" => Some(NetworkCode::SignOff),
+            "101" => Some(NetworkCode::KeyChange),
+            "301" => Some(NetworkCode::Echo),
+            _ => None,
+        }
+    }
+
+    pub fn as_field(&self) -> &'static str {
+        match self {
+            NetworkCode::SignOn => "001",
+            NetworkCode::SignOff => "002",
+            NetworkCode::KeyChange => "101",
+            NetworkCode::Echo => "301",
+        }
+    }
+}
+
+/// When to test an idle connection with an 0800 echo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Time without traffic from the peer before an echo is sent.
+    pub idle: Duration,
+    /// Echoes in a row that may go unanswered before the connection is closed.
+    pub max_missed: u32,
+}
+
+/// What to do when a heartbeat is due.
+#[derive(Debug)]
+pub enum HeartbeatAction {
+    Send(IsoMessage),
+    /// The peer missed too many echoes.
+    Close,
+}
+
+/// Network management state of one connection.
+///
+/// Answers 0800 sign-on, sign-off, key change and echo requests itself, and rejects
+/// authorization, financial and reversal requests with response code 91 until the peer signs
+/// on, if `require_sign_on` is set. Every other request, including 0800s with other codes,
+/// is left to the `MessageHandler`.
+///
+/// With a `Heartbeat` it also sends echo tests of its own while the peer is quiet, matching
+/// the 0810s by STAN.
+#[derive(Debug)]
+pub struct Session {
+    require_sign_on: bool,
+    signed_on: bool,
+    key_changes: u32,
+    heartbeat: Option<Heartbeat>,
+    last_received: Instant,
+    /// STAN of the echo waiting for an 0810.
+    echo_outstanding: Option<String>,
+    missed_echoes: u32,
+    stan: u32,
+}
+
+impl Session {
+    pub fn new(require_sign_on: bool, heartbeat: Option<Heartbeat>) -> Self {
+        Self {
+            require_sign_on,
+            signed_on: false,
+            key_changes: 0,
+            heartbeat,
+            last_received: Instant::now(),
+            echo_outstanding: None,
+            missed_echoes: 0,
+            stan: 0,
+        }
+    }
+
+    /// Notes traffic from the peer. Returns `true` if `message` answers one of our echoes and
+    /// needs no further handling.
+    pub fn received(&mut self, message: &IsoMessage) -> bool {
+        self.last_received = Instant::now();
+
+        if message.get_field(0) != Some("0810") || self.heartbeat.is_none() {
+            return false;
+        }
+
+        if self.echo_outstanding.as_deref() == message.get_field(11) {
+            debug!("Peer answered echo test");
+            self.echo_outstanding = None;
+            self.missed_echoes = 0;
+        } else {
+            warn!(
+                "Ignoring 0810 with STAN {:?} that answers no echo test",
+                message.get_field(11)
+            );
+        }
+
+        true
+    }
+
+    /// When the next heartbeat is due, if heartbeats are on.
+    pub fn heartbeat_due(&self) -> Option<Instant> {
+        self.heartbeat
+            .map(|heartbeat| self.last_received + heartbeat.idle)
+    }
+
+    /// Counts an unanswered echo as missed and builds the next one.
+    pub fn heartbeat(&mut self) -> HeartbeatAction {
+        let max_missed = match self.heartbeat {
+            Some(heartbeat) => heartbeat.max_missed,
+            None => return HeartbeatAction::Close,
+        };
+
+        if self.echo_outstanding.is_some() {
+            self.missed_echoes += 1;
+            warn!("Peer missed {} echo test(s)", self.missed_echoes);
+
+            if self.missed_echoes >= max_missed {
+                return HeartbeatAction::Close;
+            }
+        }
+
+        // Wait a whole idle period for the answer
+        self.last_received = Instant::now();
+        self.stan = self.stan % 999_999 + 1;
+        let stan = format!("{:06}", self.stan);
+
+        match echo(&stan) {
+            Ok(echo) => {
+                self.echo_outstanding = Some(stan);
+                HeartbeatAction::Send(echo)
+            }
+            Err(e) => {
+                warn!("Unable to build echo test: {}", e);
+                HeartbeatAction::Close
+            }
+        }
+    }
+
+    /// The response to send for `request` without handing it to the `MessageHandler`.
+    pub fn answer(&mut self, request: &IsoMessage) -> Option<IsoMessage> {
+        let mti = request.get_field(0)?;
+
+        let response_code = if mti == "0800" {
+            let code = request.get_field(70).and_then(NetworkCode::from_field)?;
+            self.apply(code);
+
+            "00"
+        } else if self.require_sign_on && !self.signed_on && is_financial(mti) {
+            warn!("Rejecting {} from a peer that has not signed on", mti);
+
+            NOT_SIGNED_ON
+        } else {
+            return None;
+        };
+
+        match request.to_response(response_code) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!("Unable to build response: {}", e);
+                None
+            }
+        }
+    }
+
+    fn apply(&mut self, code: NetworkCode) {
+        match code {
+            NetworkCode::SignOn => {
+                info!("Peer signed on");
+                self.signed_on = true;
+            }
+            NetworkCode::SignOff => {
+                info!("Peer signed off");
+                self.signed_on = false;
+            }
+            NetworkCode::KeyChange => {
+                self.key_changes += 1;
+                info!("Peer changed keys, {} change(s) so far", self.key_changes);
+            }
+            NetworkCode::Echo => {}
+        }
+    }
+}
+
+fn echo(stan: &str) -> Result<IsoMessage, String> {
+    let mut echo = IsoMessage::new("0800")?;
+    echo.set_field(7, &Utc::now().format("%m%d%H%M%S").to_string())?;
+    echo.set_field(11, stan)?;
+    echo.set_field(70, NetworkCode::Echo.as_field())?;
+
+    Ok(echo)
+}
+
+/// Authorization, financial and reversal messages.
+fn is_financial(mti: &str) -> bool {
+    matches!(mti.as_bytes().get(1), Some(b'1' | b'2' | b'4'))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use iso_8583_message::IsoMessage;
+
+    use super::{Heartbeat, HeartbeatAction, NetworkCode, Session};
+    use crate::test_support::get_message_from_file;
+
+    fn network_request(code: NetworkCode) -> IsoMessage {
+        let mut request = get_message_from_file("sample_messages/i2c-network-request.bin");
+        request.set_field(70, code.as_field()).unwrap();
+
+        request
+    }
+
+    fn response_code(session: &mut Session, request: &IsoMessage) -> Option<String> {
+        let response = session.answer(request)?;
+
+        response.get_field(39).map(str::to_string)
+    }
+
+    #[test]
+    fn should_reject_financial_requests_until_signed_on() {
+        let mut session = Session::new(true, None);
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        assert_eq!(response_code(&mut session, &request).as_deref(), Some("91"));
+        let sign_on = session
+            .answer(&network_request(NetworkCode::SignOn))
+            .unwrap();
+        assert_eq!(sign_on.get_field(0), Some("0810"));
+        assert_eq!(sign_on.get_field(39), Some("00"));
+        assert_eq!(response_code(&mut session, &request), None);
+
+        session.answer(&network_request(NetworkCode::SignOff));
+        assert_eq!(response_code(&mut session, &request).as_deref(), Some("91"));
+    }
+
+    #[test]
+    fn should_answer_echo_and_key_change_without_sign_on() {
+        let mut session = Session::new(true, None);
+
+        for code in [NetworkCode::Echo, NetworkCode::KeyChange] {
+            assert_eq!(
+                response_code(&mut session, &network_request(code)).as_deref(),
+                Some("00")
+            );
+        }
+        assert_eq!(session.key_changes, 1);
+    }
+
+    #[test]
+    fn should_leave_other_requests_to_handler() {
+        let mut session = Session::new(false, None);
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        let unknown_code = get_message_from_file("sample_messages/i2c-network-request.bin");
+        let token = get_message_from_file("sample_messages/i2c-token-management-request.bin");
+
+        assert_eq!(response_code(&mut session, &request), None);
+        assert_eq!(response_code(&mut session, &unknown_code), None);
+        assert_eq!(response_code(&mut Session::new(true, None), &token), None);
+    }
+
+    #[test]
+    fn should_close_after_missed_echoes() {
+        let heartbeat = Heartbeat {
+            idle: Duration::from_secs(30),
+            max_missed: 2,
+        };
+        let mut session = Session::new(false, Some(heartbeat));
+
+        let echo = match session.heartbeat() {
+            HeartbeatAction::Send(echo) => echo,
+            HeartbeatAction::Close => panic!("closed before any echo was missed"),
+        };
+        assert_eq!(echo.get_field(70), Some("301"));
+        assert!(session.received(&echo.to_response("00").unwrap()));
+
+        assert!(matches!(session.heartbeat(), HeartbeatAction::Send(_)));
+        assert!(matches!(session.heartbeat(), HeartbeatAction::Send(_)));
+        assert!(matches!(session.heartbeat(), HeartbeatAction::Close));
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+
+use iso_8583_message::IsoMessage;
+
+use crate::fields::to_fields;
+
+/// Fields holding card secrets: the PAN and extended PAN, track 2, 3 and 1 data, the PIN
+/// block and the account identification, which issuers often fill with the PAN.
+pub const MASKED_FIELDS: [usize; 7] = [2, 34, 35, 36, 45, 52, 102];
+/// Masked fields that hold an account number, of which the first six and last four digits
+/// are left showing.
+const ACCOUNT_NUMBER_FIELDS: [usize; 3] = [2, 34, 102];
+
+/// Lays a message out one field per line with the field's number, ISO 8583:1987 name,
+/// length and value, after the MTI and bitmap. Card secrets are masked unless `unmasked`
+/// is called.
+///
+/// ```text
+/// MTI     0200
+/// Bitmap  F67A44D108E0A40A 0000000004020000
+///   2  Primary account number                       15  100194*****6654
+///   3  Processing code                               6  000000
+/// ```
+pub struct Pretty<'a> {
+    message: &'a IsoMessage,
+    masked: bool,
+}
+
+pub fn pretty(message: &IsoMessage) -> Pretty<'_> {
+    Pretty {
+        message,
+        masked: true,
+    }
+}
+
+impl Pretty<'_> {
+    pub fn unmasked(mut self) -> Self {
+        self.masked = false;
+        self
+    }
+}
+
+impl fmt::Display for Pretty<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = to_fields(self.message);
+
+        writeln!(
+            f,
+            "MTI     {}",
+            fields.get(&0).map_or("????", String::as_str)
+        )?;
+        write!(f, "Bitmap  {}", bitmap(fields.keys().copied()))?;
+
+        for (field, value) in fields.range(2..) {
+            let value = if self.masked && MASKED_FIELDS.contains(field) {
+                mask(*field, value)
+            } else {
+                value.clone()
+            };
+
+            write!(
+                f,
+                "\n{:>3}  {:<42} {:>4}  {}",
+                field,
+                name(*field),
+                value.len(),
+                value
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The primary bitmap in hex, then the secondary one if any field above 64 is set.
+fn bitmap(fields: impl Iterator<Item = usize>) -> String {
+    let mut bitmap = [0u8; 16];
+
+    for field in fields.filter(|field| (2..=128).contains(field)) {
+        bitmap[(field - 1) / 8] |= 0x80 >> ((field - 1) % 8);
+
+        if field > 64 {
+            bitmap[0] |= 0x80;
+        }
+    }
+
+    let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02X}", b)).collect() };
+
+    if bitmap[0] & 0x80 != 0 {
+        format!("{} {}", hex(&bitmap[..8]), hex(&bitmap[8..]))
+    } else {
+        hex(&bitmap[..8])
+    }
+}
+
+/// Keeps the first six and last four digits of an account number, as receipts do, and hides
+/// the rest of it and all of anything else.
+fn mask(field: usize, value: &str) -> String {
+    let length = value.chars().count();
+
+    if ACCOUNT_NUMBER_FIELDS.contains(&field) && length > 10 {
+        value
+            .chars()
+            .enumerate()
+            .map(|(index, c)| {
+                if index < 6 || index >= length - 4 {
+                    c
+                } else {
+                    '*'
+                }
+            })
+            .collect()
+    } else {
+        "*".repeat(length)
+    }
+}
+
+/// The ISO 8583:1987 name of `field`.
+pub fn name(field: usize) -> &'static str {
+    match field {
+        0 => "Message type indicator",
+        1 => "Secondary bitmap",
+        2 => "Primary account number",
+        3 => "Processing code",
+        4 => "Amount, transaction",
+        5 => "Amount, settlement",
+        6 => "Amount, cardholder billing",
+        7 => "Transmission date and time",
+        8 => "Amount, cardholder billing fee",
+        9 => "Conversion rate, settlement",
+        10 => "Conversion rate, cardholder billing",
+        11 => "System trace audit number",
+        12 => "Time, local transaction",
+        13 => "Date, local transaction",
+        14 => "Date, expiration",
+        15 => "Date, settlement",
+        16 => "Date, conversion",
+        17 => "Date, capture",
+        18 => "Merchant type",
+        19 => "Acquiring institution country code",
+        20 => "PAN extended, country code",
+        21 => "Forwarding institution country code",
+        22 => "Point of service entry mode",
+        23 => "Application PAN sequence number",
+        24 => "Network international identifier",
+        25 => "Point of service condition code",
+        26 => "Point of service capture code",
+        27 => "Authorizing identification response length",
+        28 => "Amount, transaction fee",
+        29 => "Amount, settlement fee",
+        30 => "Amount, transaction processing fee",
+        31 => "Amount, settlement processing fee",
+        32 => "Acquiring institution identification code",
+        33 => "Forwarding institution identification code",
+        34 => "Primary account number, extended",
+        35 => "Track 2 data",
+        36 => "Track 3 data",
+        37 => "Retrieval reference number",
+        38 => "Authorization identification response",
+        39 => "Response code",
+        40 => "Service restriction code",
+        41 => "Card acceptor terminal identification",
+        42 => "Card acceptor identification code",
+        43 => "Card acceptor name/location",
+        44 => "Additional response data",
+        45 => "Track 1 data",
+        46 => "Additional data, ISO",
+        47 => "Additional data, national",
+        48 => "Additional data, private",
+        49 => "Currency code, transaction",
+        50 => "Currency code, settlement",
+        51 => "Currency code, cardholder billing",
+        52 => "Personal identification number data",
+        53 => "Security related control information",
+        54 => "Additional amounts",
+        55 | 56 => "Reserved, ISO",
+        57..=60 => "Reserved, national",
+        61..=63 => "Reserved, private",
+        64 | 128 => "Message authentication code",
+        65 => "Bitmap, extended",
+        66 => "Settlement code",
+        67 => "Extended payment code",
+        68 => "Receiving institution country code",
+        69 => "Settlement institution country code",
+        70 => "Network management information code",
+        71 => "Message number",
+        72 => "Message number, last",
+        73 => "Date, action",
+        74 => "Credits, number",
+        75 => "Credits, reversal number",
+        76 => "Debits, number",
+        77 => "Debits, reversal number",
+        78 => "Transfer, number",
+        79 => "Transfer, reversal number",
+        80 => "Inquiries, number",
+        81 => "Authorizations, number",
+        82 => "Credits, processing fee amount",
+        83 => "Credits, transaction fee amount",
+        84 => "Debits, processing fee amount",
+        85 => "Debits, transaction fee amount",
+        86 => "Credits, amount",
+        87 => "Credits, reversal amount",
+        88 => "Debits, amount",
+        89 => "Debits, reversal amount",
+        90 => "Original data elements",
+        91 => "File update code",
+        92 => "File security code",
+        93 => "Response indicator",
+        94 => "Service indicator",
+        95 => "Replacement amounts",
+        96 => "Message security code",
+        97 => "Amount, net settlement",
+        98 => "Payee",
+        99 => "Settlement institution identification code",
+        100 => "Receiving institution identification code",
+        101 => "File name",
+        102 => "Account identification 1",
+        103 => "Account identification 2",
+        104 => "Transaction description",
+        105..=111 => "Reserved, ISO",
+        112..=119 => "Reserved, national",
+        120..=127 => "Reserved, private",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{bitmap, pretty};
+    use crate::test_support::get_message_from_file;
+
+    #[test]
+    fn should_mask_card_secrets_unless_unmasked() {
+        let message = get_message_from_file("sample_messages/i2c-financial-request.bin");
+        let pan = message.get_field(2).unwrap();
+        let masked_pan = format!(
+            "{}{}{}",
+            &pan[..6],
+            "*".repeat(pan.len() - 10),
+            &pan[pan.len() - 4..]
+        );
+
+        let pan_line = |printed: &str| {
+            printed
+                .lines()
+                .find(|line| line.starts_with("  2  Primary account number"))
+                .map(str::to_string)
+                .unwrap()
+        };
+
+        let masked = pretty(&message).to_string();
+        let unmasked = pretty(&message).unmasked().to_string();
+
+        assert!(masked.starts_with("MTI     0200\nBitmap  "));
+        assert!(pan_line(&masked).ends_with(&format!("15  {}", masked_pan)));
+        // Field 102 of the sample holds the PAN as well
+        assert!(masked.lines().all(|line| !line.contains(pan)));
+        assert!(pan_line(&unmasked).ends_with(pan));
+    }
+
+    #[test]
+    fn should_add_secondary_bitmap_for_fields_above_64() {
+        assert_eq!(bitmap([0, 2, 3, 4].into_iter()), "7000000000000000");
+        assert_eq!(
+            bitmap([0, 7, 70].into_iter()),
+            "8200000000000000 0400000000000000"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use futures::StreamExt;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+use tokio_util::codec::FramedRead;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    capture::{CaptureWriter, Direction, Record},
+    message_machine::{FramingError, IsoFrameCodec, RawFrameCodec},
+};
+
+/// Accepts connections and relays each one frame by frame to a connection of its own to
+/// `upstream`, recording every frame if given a capture.
+///
+/// Frames are split on their length prefix and relayed byte for byte, so the host gets
+/// exactly what the peer sent, including messages that do not parse, and the capture holds
+/// what was on the wire.
+pub struct Proxy {
+    listener: TcpListener,
+    upstream: String,
+    codec: IsoFrameCodec,
+    capture: Option<Arc<CaptureWriter>>,
+    connections: AtomicU32,
+}
+
+impl Proxy {
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        upstream: &str,
+        codec: IsoFrameCodec,
+    ) -> Result<Self, io::Error> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            upstream: upstream.to_string(),
+            codec,
+            capture: None,
+            connections: AtomicU32::new(0),
+        })
+    }
+
+    /// Records every relayed frame in `capture`, which other proxies may share.
+    pub fn capture(mut self, capture: Arc<CaptureWriter>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the listener fails, relaying each one on its own task.
+    pub async fn run(self) -> Result<(), io::Error> {
+        loop {
+            let (client, client_addr) = self.listener.accept().await?;
+            let connection = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+            let span = info_span!("connection", id = connection, peer = %client_addr);
+            span.in_scope(|| info!("Connection {} made on {}", connection, client_addr));
+
+            let upstream = self.upstream.clone();
+            let codec = self.codec.clone();
+            let capture = self.capture.clone();
+            tokio::spawn(
+                async move {
+                    match relay_connection(client, &upstream, codec, capture, connection).await {
+                        Ok(_) => info!("Connection {} closed", connection),
+                        Err(e) => warn!(
+                            "An {} error occurred relaying connection {}. Dropping connection",
+                            e, connection
+                        ),
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+async fn relay_connection(
+    client: TcpStream,
+    upstream: &str,
+    codec: IsoFrameCodec,
+    capture: Option<Arc<CaptureWriter>>,
+    connection: u32,
+) -> Result<(), io::Error> {
+    let upstream = TcpStream::connect(upstream).await?;
+    debug!(
+        "Connection {} relayed to {}",
+        connection,
+        upstream.peer_addr()?
+    );
+
+    let (client_reader, client_writer) = client.into_split();
+    let (upstream_reader, upstream_writer) = upstream.into_split();
+    let relay = |reader, writer, direction| {
+        Relay {
+            codec: codec.clone(),
+            capture: capture.clone(),
+            connection,
+            direction,
+        }
+        .run(reader, writer)
+    };
+
+    // A side that closes has its close passed on, and the other side is still relayed until
+    // it closes too, so responses to the last requests get through
+    tokio::try_join!(
+        relay(client_reader, upstream_writer, Direction::FromClient),
+        relay(upstream_reader, client_writer, Direction::FromUpstream),
+    )?;
+
+    Ok(())
+}
+
+struct Relay {
+    codec: IsoFrameCodec,
+    capture: Option<Arc<CaptureWriter>>,
+    connection: u32,
+    direction: Direction,
+}
+
+impl Relay {
+    async fn run<R, W>(self, reader: R, mut writer: W) -> Result<(), io::Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut reader = FramedRead::new(reader, RawFrameCodec::new(self.codec));
+
+        while let Some(frame) = reader.next().await {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(FramingError::Io(e)) => return Err(e),
+                // The next frame cannot be found after a bad length prefix
+                Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+            };
+
+            if let Some(capture) = &self.capture {
+                let record = Record::now(self.connection, self.direction, frame.to_vec());
+
+                if let Err(e) = capture.record(record).await {
+                    warn!("Unable to record frame: {}", e);
+                }
+            }
+
+            writer.write_all(&frame).await?;
+        }
+
+        writer.shutdown().await
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use iso_8583_message::IsoMessage;
+use tokio::{
+    io,
+    task::JoinSet,
+    time::{sleep_until, Instant},
+};
+use tokio_util::codec::Decoder;
+use tracing::{debug, info};
+
+use crate::{
+    capture::{Direction, Record},
+    correlator::{is_response, CorrelationKey, Correlator, SendError},
+    fields::to_fields,
+    message_machine::IsoFrameCodec,
+};
+
+/// How long a replayed request waits for its response unless told otherwise.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request from the capture, the response recorded for it and the one the target gave.
+#[derive(Debug)]
+pub struct Replayed {
+    pub connection: u32,
+    pub request: IsoMessage,
+    /// `None` if the capture holds no response to the request.
+    pub recorded: Option<IsoMessage>,
+    pub response: Result<IsoMessage, SendError>,
+    /// Fields, the MTI being field 0, set to different values in the two responses.
+    pub differences: Vec<usize>,
+}
+
+impl Replayed {
+    /// The target answered as recorded, ignored fields aside.
+    pub fn matched(&self) -> bool {
+        self.recorded.is_some() && self.response.is_ok() && self.differences.is_empty()
+    }
+}
+
+/// A request due to be replayed and the response recorded for it.
+struct Pending {
+    at: Duration,
+    request: IsoMessage,
+    recorded: Option<IsoMessage>,
+}
+
+/// Plays the requests in a capture against a target, one connection to it for every
+/// connection in the capture, and compares the responses with those recorded.
+///
+/// Requests go out with the gaps between them in the capture, divided by the speed. Responses
+/// are tied to requests as `Correlator` does, and requests the peer answered for the host,
+/// like echo tests, are left out.
+pub struct Replay {
+    target: String,
+    codec: IsoFrameCodec,
+    speed: f64,
+    timeout: Duration,
+    ignored_fields: Vec<usize>,
+}
+
+impl Replay {
+    pub fn new(target: &str, codec: IsoFrameCodec) -> Self {
+        Self {
+            target: target.to_string(),
+            codec,
+            speed: 1.0,
+            timeout: RESPONSE_TIMEOUT,
+            ignored_fields: Vec::new(),
+        }
+    }
+
+    /// How many times faster than recorded to replay. Defaults to 1, the original timing.
+    /// `run` fails unless it is finite and above 0.
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// How long each request waits for a response. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Fields left out when comparing responses, like approval codes the target makes up.
+    pub fn ignore_fields(mut self, fields: &[usize]) -> Self {
+        self.ignored_fields = fields.to_vec();
+        self
+    }
+
+    /// Replays `records`, returning every request in the order it was sent.
+    pub async fn run(&self, records: &[Record]) -> Result<Vec<Replayed>, io::Error> {
+        if !self.speed.is_finite() || self.speed <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("speed must be above 0, not {}", self.speed),
+            ));
+        }
+
+        let connections = self.pending(records)?;
+        let start = Instant::now();
+        let mut replays = JoinSet::new();
+
+        info!(
+            "Replaying {} connection(s) against {} at {}x speed",
+            connections.len(),
+            self.target,
+            self.speed
+        );
+
+        for (connection, pending) in connections {
+            replays.spawn(replay_connection(
+                self.target.clone(),
+                self.codec.clone(),
+                self.timeout,
+                start,
+                connection,
+                pending,
+            ));
+        }
+
+        let mut replayed = Vec::new();
+
+        while let Some(result) = replays.join_next().await {
+            replayed.extend(result??);
+        }
+
+        replayed.sort_by_key(|(at, replayed): &(Duration, Replayed)| (*at, replayed.connection));
+
+        Ok(replayed
+            .into_iter()
+            .map(|(_, mut replayed)| {
+                replayed.differences = self.differences(&replayed);
+                replayed
+            })
+            .collect())
+    }
+
+    /// The requests in `records` by connection, each with the response recorded for it.
+    fn pending(&self, records: &[Record]) -> Result<BTreeMap<u32, Vec<Pending>>, io::Error> {
+        let first = records.first().map_or(0, |record| record.timestamp_us);
+        let mut connections = BTreeMap::<u32, Vec<Pending>>::new();
+        let mut unanswered = HashMap::<(u32, CorrelationKey), VecDeque<usize>>::new();
+
+        for (index, record) in records.iter().enumerate() {
+            let message = self.decode(&record.frame).map_err(|reason| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("record {}: {}", index + 1, reason),
+                )
+            })?;
+            let key = match CorrelationKey::of(&message) {
+                Some(key) => (record.connection, key),
+                None => continue,
+            };
+
+            match (record.direction, is_response(&message)) {
+                (Direction::FromClient, false) => {
+                    let pending = connections.entry(record.connection).or_default();
+                    unanswered.entry(key).or_default().push_back(pending.len());
+                    pending.push(Pending {
+                        at: Duration::from_micros(record.timestamp_us.saturating_sub(first))
+                            .div_f64(self.speed),
+                        request: message,
+                        recorded: None,
+                    });
+                }
+                (Direction::FromUpstream, true) => {
+                    match unanswered.get_mut(&key).and_then(VecDeque::pop_front) {
+                        Some(request) => {
+                            connections.entry(record.connection).or_default()[request].recorded =
+                                Some(message)
+                        }
+                        None => debug!("Recorded response {} matches no request", index + 1),
+                    }
+                }
+                _ => debug!("Not replaying record {}", index + 1),
+            }
+        }
+
+        Ok(connections)
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<IsoMessage, String> {
+        match self.codec.clone().decode(&mut BytesMut::from(frame)) {
+            Ok(Some(frame)) => Ok(frame.message),
+            Ok(None) => Err("incomplete frame".to_string()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn differences(&self, replayed: &Replayed) -> Vec<usize> {
+        let (recorded, response) = match (&replayed.recorded, &replayed.response) {
+            (Some(recorded), Ok(response)) => (to_fields(recorded), to_fields(response)),
+            _ => return Vec::new(),
+        };
+        let mut fields: Vec<usize> = recorded.keys().chain(response.keys()).copied().collect();
+        fields.sort_unstable();
+        fields.dedup();
+
+        fields
+            .into_iter()
+            .filter(|field| !self.ignored_fields.contains(field))
+            .filter(|field| recorded.get(field) != response.get(field))
+            .collect()
+    }
+}
+
+async fn replay_connection(
+    target: String,
+    codec: IsoFrameCodec,
+    timeout: Duration,
+    start: Instant,
+    connection: u32,
+    pending: Vec<Pending>,
+) -> Result<Vec<(Duration, Replayed)>, io::Error> {
+    if let Some(first) = pending.first() {
+        sleep_until(start + first.at).await;
+    }
+
+    let correlator = Arc::new(Correlator::connect(&target, codec).await?.timeout(timeout));
+    let mut sends = JoinSet::new();
+    debug!("Replaying connection {} to {}", connection, target);
+
+    // Each request goes out on time however long the ones before it wait for a response
+    for pending in pending {
+        sleep_until(start + pending.at).await;
+
+        let correlator = correlator.clone();
+        sends.spawn(async move {
+            let response = correlator.send(pending.request.clone()).await;
+
+            (
+                pending.at,
+                Replayed {
+                    connection,
+                    request: pending.request,
+                    recorded: pending.recorded,
+                    response,
+                    differences: Vec::new(),
+                },
+            )
+        });
+    }
+
+    let mut replayed = Vec::new();
+
+    while let Some(result) = sends.join_next().await {
+        replayed.push(result?);
+    }
+
+    Ok(replayed)
+}
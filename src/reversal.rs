@@ -0,0 +1,304 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use iso_8583_message::IsoMessage;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::correlator::{Correlator, SendError};
+
+/// Fields of the original left out of its reversal: the authorization ID and response code,
+/// which were never received, and the track data, PIN block and chip data that only belong
+/// on the card-present request itself.
+const NOT_REVERSED: [usize; 7] = [35, 36, 38, 39, 45, 52, 55];
+
+/// How a timed out request is reversed.
+///
+/// ```toml
+/// [reversals]
+/// auto = true
+/// max_attempts = 5
+/// retry_interval_ms = 1000
+/// backoff = 2.0
+/// max_interval_ms = 60000
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Reverse 0100 and 0200 requests that time out.
+    pub auto: bool,
+    /// Reversals sent at most, the first as an 0420 and the rest as 0421 repeats.
+    pub max_attempts: u32,
+    /// Wait after the first unanswered reversal.
+    pub retry_interval_ms: u64,
+    /// Each wait after that is this many times longer than the last.
+    pub backoff: f64,
+    /// Longest wait between attempts, however many there have been.
+    pub max_interval_ms: u64,
+}
+
+impl RetryPolicy {
+    /// The wait before attempt `attempt`, counting from 1.
+    pub fn wait_before(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(2).min(i32::MAX as u32) as i32;
+        let wait_ms = self.retry_interval_ms as f64 * self.backoff.powi(exponent);
+
+        // Infinite once the backoff has grown past what an f64 holds
+        if wait_ms.is_finite() && wait_ms < self.max_interval_ms as f64 {
+            Duration::from_secs_f64(wait_ms / 1_000.0)
+        } else {
+            Duration::from_millis(self.max_interval_ms)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".to_string());
+        }
+        if self.backoff.is_nan() || self.backoff < 1.0 {
+            return Err("backoff must be at least 1".to_string());
+        }
+        if self.max_interval_ms < self.retry_interval_ms {
+            return Err("max_interval_ms must be at least retry_interval_ms".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            auto: true,
+            max_attempts: 5,
+            retry_interval_ms: 1_000,
+            backoff: 2.0,
+            max_interval_ms: 60_000,
+        }
+    }
+}
+
+/// How a request sent with `send_or_reverse` ended.
+#[derive(Debug)]
+pub enum Exchange {
+    Answered(IsoMessage),
+    /// The request timed out and was reversed. Holds the reversal's response, or the error
+    /// from its last attempt.
+    Reversed(Result<IsoMessage, SendError>),
+}
+
+/// Builds the 0420 reversal of `original`, carrying its MTI, STAN, transmission datetime and
+/// institution IDs in field 90.
+pub fn reversal_for(original: &IsoMessage) -> Result<IsoMessage, String> {
+    // Field 90 has 11 digits for each institution ID, padded with zeros
+    let institution = |number: usize| {
+        let id = original.get_field(number).unwrap_or_default();
+
+        if id.len() > 11 {
+            Err(format!(
+                "field {} is {} digits, field 90 holds at most 11",
+                number,
+                id.len()
+            ))
+        } else {
+            Ok(id)
+        }
+    };
+    let mti = original.get_field(0).ok_or("original has no MTI")?;
+    let stan = original.get_field(11).ok_or("original has no STAN")?;
+    let transmission_datetime = original
+        .get_field(7)
+        .ok_or("original has no transmission datetime")?;
+
+    let mut reversal = original.clone();
+    reversal.set_field(0, "0420")?;
+    reversal.set_field(
+        90,
+        &format!(
+            "{}{}{}{:0>11}{:0>11}",
+            mti,
+            stan,
+            transmission_datetime,
+            institution(32)?,
+            institution(33)?
+        ),
+    )?;
+    // The reversal is a transmission of its own
+    reversal.set_field(7, &Utc::now().format("%m%d%H%M%S").to_string())?;
+    for number in NOT_REVERSED {
+        reversal.remove_field(number);
+    }
+
+    Ok(reversal)
+}
+
+/// Sends `request`, reversing it if it is an 0100 or 0200 that times out and
+/// `policy.auto` is set.
+pub async fn send_or_reverse(
+    correlator: &Correlator,
+    request: IsoMessage,
+    policy: &RetryPolicy,
+) -> Result<Exchange, SendError> {
+    let reversible = policy.auto && matches!(request.get_field(0), Some("0100" | "0200"));
+
+    match correlator.send(request.clone()).await {
+        Ok(response) => Ok(Exchange::Answered(response)),
+        Err(SendError::Timeout) if reversible => {
+            warn!("Request timed out, reversing it");
+            Ok(Exchange::Reversed(
+                reverse(correlator, &request, policy).await,
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Sends the reversal of `original`, repeating it as an 0421 until it is answered or
+/// `policy.max_attempts` have gone unanswered.
+pub async fn reverse(
+    correlator: &Correlator,
+    original: &IsoMessage,
+    policy: &RetryPolicy,
+) -> Result<IsoMessage, SendError> {
+    let mut reversal = reversal_for(original).map_err(SendError::Invalid)?;
+    let mut attempt = 1;
+
+    loop {
+        match correlator.send(reversal.clone()).await {
+            Err(SendError::Timeout) if attempt < policy.max_attempts => {}
+            result => return result,
+        }
+
+        attempt += 1;
+        sleep(policy.wait_before(attempt)).await;
+        info!("Repeating reversal, attempt {}", attempt);
+
+        reversal.set_field(0, "0421").map_err(SendError::Invalid)?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+
+    use super::{reversal_for, send_or_reverse, Exchange, RetryPolicy};
+    use crate::{
+        correlator::Correlator,
+        message_machine::{IsoFrame, IsoFrameCodec},
+        test_support::get_message_from_file,
+    };
+
+    #[test]
+    fn should_fill_original_data_elements() {
+        let original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        let reversal = reversal_for(&original).unwrap();
+        let original_data = reversal.get_field(90).unwrap();
+
+        assert_eq!(reversal.get_field(0), Some("0420"));
+        assert_eq!(original_data.len(), 42);
+        assert_eq!(&original_data[..20], "01000163720122132918");
+        assert_eq!(reversal.get_field(11), original.get_field(11));
+    }
+
+    #[test]
+    fn should_leave_card_present_fields_out() {
+        let mut original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        for (number, value) in [
+            (35, "4000000000000002=2512"),
+            (36, "0123456789"),
+            (45, "B4000000000000002^CARD^2512"),
+            (52, "0123456789abcdef"),
+            (55, "9f2608"),
+        ] {
+            original.set_field(number, value).unwrap();
+        }
+
+        let reversal = reversal_for(&original).unwrap();
+
+        for number in [35, 36, 38, 39, 45, 52, 55] {
+            assert_eq!(reversal.get_field(number), None, "field {}", number);
+        }
+        assert_eq!(reversal.get_field(2), original.get_field(2));
+    }
+
+    #[test]
+    fn should_reject_institution_ids_too_long_for_original_data() {
+        let mut original = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+        original.set_field(33, "123456789012").unwrap();
+
+        let error = reversal_for(&original).unwrap_err();
+
+        assert!(error.contains("field 33"), "{}", error);
+    }
+
+    #[test]
+    fn should_back_off_between_attempts() {
+        let policy = RetryPolicy {
+            retry_interval_ms: 100,
+            backoff: 2.0,
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(policy.wait_before(2), Duration::from_millis(100));
+        assert_eq!(policy.wait_before(4), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn should_cap_wait_at_max_interval() {
+        let policy = RetryPolicy {
+            retry_interval_ms: 100,
+            backoff: 2.0,
+            max_interval_ms: 5_000,
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(policy.wait_before(40), Duration::from_secs(5));
+        assert_eq!(policy.wait_before(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn should_repeat_reversal_until_answered() {
+        let (ours, theirs) = duplex(64 * 1024);
+        let correlator =
+            Correlator::new(ours, IsoFrameCodec::default()).timeout(Duration::from_millis(20));
+        let mut host = Framed::new(theirs, IsoFrameCodec::default());
+        let policy = RetryPolicy {
+            retry_interval_ms: 1,
+            ..RetryPolicy::default()
+        };
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        // Leaves the request and the first reversal unanswered
+        let host = tokio::spawn(async move {
+            let mut mtis = Vec::new();
+
+            while mtis.len() < 3 {
+                let request = host.next().await.unwrap().unwrap().message;
+                mtis.push(request.get_field(0).unwrap().to_string());
+
+                if mtis.len() == 3 {
+                    let response = IsoFrame::from(request.to_response("00").unwrap());
+                    host.send(response).await.unwrap();
+                }
+            }
+
+            mtis
+        });
+
+        let exchange = send_or_reverse(&correlator, request, &policy)
+            .await
+            .unwrap();
+
+        assert_eq!(host.await.unwrap(), ["0100", "0420", "0421"]);
+        match exchange {
+            Exchange::Reversed(Ok(response)) => assert_eq!(response.get_field(39), Some("00")),
+            other => panic!("expected an answered reversal, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::{collections::BTreeMap, fs, path::Path, str::FromStr};
+
+use async_trait::async_trait;
+use iso_8583_message::IsoMessage;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::{
+    config::ConfigError,
+    latency::{Latency, LatencyProfiles},
+    message_handler::MessageHandler,
+};
+
+/// Rules read from a TOML file, tried in the order they are written.
+///
+/// ```toml
+/// [[rule]]
+/// name = "insufficient funds"
+/// response_code = "51"
+///
+/// [rule.match]
+/// mti = "0100"
+/// pan_prefix = "100194"
+/// amount_min = 100000
+///
+/// [rule.fields]
+/// 38 = "      "
+///
+/// [rule.latency]
+/// kind = "uniform"
+/// min_ms = 200
+/// max_ms = 800
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Only used in logs.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, rename = "match")]
+    pub matcher: Matcher,
+    /// Field 39 of the response.
+    pub response_code: String,
+    /// Set on the response after `to_response`, keyed by field number.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Overrides the latency for the request's MTI.
+    #[serde(default)]
+    pub latency: Option<Latency>,
+}
+
+/// Conditions on the request, all of which must hold for the rule to apply. A matcher with
+/// nothing set matches every request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Matcher {
+    pub mti: Option<String>,
+    /// Exact PAN in field 2.
+    pub pan: Option<String>,
+    pub pan_prefix: Option<String>,
+    /// Smallest amount in field 4, in minor units.
+    pub amount_min: Option<u64>,
+    /// Largest amount in field 4, in minor units.
+    pub amount_max: Option<u64>,
+    /// Leading digits of field 3, e.g. `"01"` for cash withdrawals.
+    pub processing_code: Option<String>,
+    /// Merchant category code in field 18.
+    pub mcc: Option<String>,
+    /// Terminal ID in field 41, ignoring trailing padding.
+    pub terminal_id: Option<String>,
+}
+
+impl Rules {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+
+        contents.parse()
+    }
+
+    /// The first rule that matches `request`.
+    pub fn find(&self, request: &IsoMessage) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matcher.matches(request))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for rule in &self.rules {
+            let invalid = |reason: String| {
+                ConfigError::Invalid(format!("rule {}: {}", rule.describe(), reason))
+            };
+
+            if rule.response_code.len() != 2 {
+                return Err(invalid(format!(
+                    "response_code must be 2 characters, got {:?}",
+                    rule.response_code
+                )));
+            }
+            if let Some(latency) = &rule.latency {
+                latency.validate().map_err(invalid)?;
+            }
+            for field in rule.fields.keys() {
+                match field.parse::<usize>() {
+                    Ok(2..=128) => {}
+                    _ => return Err(invalid(format!("{:?} is not a field number", field))),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Rules {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules: Rules = toml::from_str(s).map_err(ConfigError::Parse)?;
+        rules.validate()?;
+
+        Ok(rules)
+    }
+}
+
+impl Rule {
+    fn describe(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{:?}", name),
+            None => format!("with response code {:?}", self.response_code),
+        }
+    }
+
+    /// Builds the response for a request this rule matched.
+    fn respond(&self, request: &IsoMessage) -> Result<IsoMessage, String> {
+        let mut response = request.to_response(&self.response_code)?;
+
+        for (field, value) in &self.fields {
+            let field = field
+                .parse()
+                .map_err(|_| format!("bad field {:?}", field))?;
+            response.set_field(field, value)?;
+        }
+
+        Ok(response)
+    }
+}
+
+impl Matcher {
+    pub fn matches(&self, request: &IsoMessage) -> bool {
+        let field = |number: usize| request.get_field(number);
+
+        matches_exact(&self.mti, field(0))
+            && matches_exact(&self.pan, field(2))
+            && matches_prefix(&self.pan_prefix, field(2))
+            && matches_prefix(&self.processing_code, field(3))
+            && matches_exact(&self.mcc, field(18))
+            && matches_exact(&self.terminal_id, field(41).map(str::trim_end))
+            && self.matches_amount(field(4))
+    }
+
+    fn matches_amount(&self, amount: Option<&str>) -> bool {
+        if self.amount_min.is_none() && self.amount_max.is_none() {
+            return true;
+        }
+
+        match amount.and_then(|amount| amount.parse::<u64>().ok()) {
+            Some(amount) => {
+                amount >= self.amount_min.unwrap_or(u64::MIN)
+                    && amount <= self.amount_max.unwrap_or(u64::MAX)
+            }
+            None => false,
+        }
+    }
+}
+
+fn matches_exact(expected: &Option<String>, actual: Option<&str>) -> bool {
+    match (expected, actual) {
+        (None, _) => true,
+        (Some(expected), Some(actual)) => actual == expected,
+        (Some(_), None) => false,
+    }
+}
+
+fn matches_prefix(prefix: &Option<String>, actual: Option<&str>) -> bool {
+    match (prefix, actual) {
+        (None, _) => true,
+        (Some(prefix), Some(actual)) => actual.starts_with(prefix.as_str()),
+        (Some(_), None) => false,
+    }
+}
+
+/// Answers with the first matching rule, or with `fallback` when none match, after waiting
+/// for the rule's latency or the one `latencies` has for the request.
+pub struct RuleEngine {
+    rules: Rules,
+    latencies: LatencyProfiles,
+    fallback: Box<dyn MessageHandler>,
+}
+
+impl RuleEngine {
+    pub fn new(
+        rules: Rules,
+        latencies: LatencyProfiles,
+        fallback: impl MessageHandler + 'static,
+    ) -> Self {
+        Self {
+            rules,
+            latencies,
+            fallback: Box::new(fallback),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageHandler for RuleEngine {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        let rule = self.rules.find(request);
+
+        if let Some(rule) = rule {
+            debug!("Matched rule {}", rule.describe());
+        }
+
+        let delay = match self
+            .latencies
+            .sample(request, rule.and_then(|rule| rule.latency.as_ref()))
+        {
+            Some(delay) => delay,
+            None => {
+                debug!("Not answering request");
+                return None;
+            }
+        };
+        sleep(delay).await;
+
+        let rule = match rule {
+            Some(rule) => rule,
+            None => return self.fallback.handle(request).await,
+        };
+
+        match rule.respond(request) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!(
+                    "Unable to build response for rule {}: {}",
+                    rule.describe(),
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Matcher, RuleEngine, Rules};
+    use crate::{
+        config::ConfigError, latency::LatencyProfiles, message_handler::Approve,
+        test_support::get_message_from_file, MessageHandler,
+    };
+
+    const RULES: &str = r#"
+        [[rule]]
+        name = "big purchases"
+        response_code = "51"
+        [rule.match]
+        mti = "0100"
+        amount_min = 10000
+
+        [[rule]]
+        name = "fuel"
+        response_code = "05"
+        [rule.match]
+        mcc = "5541"
+        terminal_id = "TERMID01"
+
+        [[rule]]
+        name = "refer everything else"
+        response_code = "01"
+        [rule.fields]
+        38 = "REFER1"
+    "#;
+
+    #[test]
+    fn should_use_first_matching_rule() {
+        let rules: Rules = RULES.parse().unwrap();
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        let rule = rules.find(&request).unwrap();
+
+        assert_eq!(rule.name.as_deref(), Some("big purchases"));
+    }
+
+    #[test]
+    fn should_match_every_condition() {
+        let request =
+            get_message_from_file("sample_messages/i2c-authorization-mastercard-request.bin");
+        let matcher = Matcher {
+            mti: Some("0100".to_string()),
+            pan_prefix: Some("511572".to_string()),
+            amount_min: Some(7786),
+            amount_max: Some(7786),
+            processing_code: Some("00".to_string()),
+            mcc: Some("5812".to_string()),
+            terminal_id: Some("00000002".to_string()),
+            ..Matcher::default()
+        };
+
+        assert!(matcher.matches(&request));
+        assert!(!Matcher {
+            amount_max: Some(7785),
+            ..matcher.clone()
+        }
+        .matches(&request));
+        assert!(!Matcher {
+            pan: Some("5115720011872343".to_string()),
+            ..matcher
+        }
+        .matches(&request));
+    }
+
+    #[tokio::test]
+    async fn should_apply_rule_response_code_and_fields() {
+        let engine = RuleEngine::new(
+            RULES.parse().unwrap(),
+            LatencyProfiles::default(),
+            Approve::new(Duration::ZERO),
+        );
+        let request = get_message_from_file("sample_messages/i2c-reversal-mastercard-request.bin");
+
+        let response = engine.handle(&request).await.unwrap();
+
+        assert_eq!(response.get_field(39), Some("01"));
+        assert_eq!(response.get_field(38), Some("REFER1"));
+    }
+
+    #[tokio::test]
+    async fn should_use_fallback_when_nothing_matches() {
+        let rules: Rules = "[[rule]]\nresponse_code = \"05\"\n[rule.match]\nmti = \"0200\"\n"
+            .parse()
+            .unwrap();
+        let engine = RuleEngine::new(
+            rules,
+            LatencyProfiles::default(),
+            Approve::new(Duration::ZERO),
+        );
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        let response = engine.handle(&request).await.unwrap();
+
+        assert_eq!(response.get_field(39), Some("00"));
+    }
+
+    #[tokio::test]
+    async fn should_not_answer_when_rule_latency_is_never() {
+        let rules: Rules = "[[rule]]\nresponse_code = \"00\"\n[rule.latency]\nkind = \"never\"\n"
+            .parse()
+            .unwrap();
+        let engine = RuleEngine::new(
+            rules,
+            LatencyProfiles::default(),
+            Approve::new(Duration::ZERO),
+        );
+        let request = get_message_from_file("sample_messages/i2c-authorization-request.bin");
+
+        assert!(engine.handle(&request).await.is_none());
+    }
+
+    #[test]
+    fn should_reject_bad_field_number() {
+        let results =
+            "[[rule]]\nresponse_code = \"05\"\n[rule.fields]\n1 = \"x\"\n".parse::<Rules>();
+
+        assert!(matches!(results, Err(ConfigError::Invalid(_))));
+    }
+}
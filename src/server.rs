@@ -0,0 +1,418 @@
+use std::{future, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use iso_8583_message::IsoMessage;
+use tokio::{
+    io::{self, ReadHalf},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{OwnedPermit, Sender},
+    task::{JoinError, JoinSet},
+    time::{sleep_until, Instant},
+};
+use tokio_util::codec::{Decoder, FramedRead};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+use crate::{
+    connection_writer,
+    length_prefix::LengthPrefix,
+    message_handler::{Approve, MessageHandler},
+    message_header::{HeaderFormat, MessageHeader},
+    message_helpers::format_error_response,
+    message_machine::{FrameWriter, FramingError, IsoFrame, IsoFrameCodec},
+    network::{Heartbeat, HeartbeatAction, Session},
+    pretty::pretty,
+    MAX_MESSAGE_SIZE,
+};
+
+/// Responses a connection may have waiting to be written before it stops reading requests.
+const RESPONSE_QUEUE_SIZE: usize = 256;
+
+/// Configures a `Server` before binding it. Servers bound from clones of one builder share its
+/// handler, and with it any state the handler keeps.
+#[derive(Clone)]
+pub struct ServerBuilder {
+    length_prefix: LengthPrefix,
+    header_format: Option<HeaderFormat>,
+    max_message_size: usize,
+    handler: Arc<dyn MessageHandler>,
+    response_queue_size: usize,
+    require_sign_on: bool,
+    heartbeat: Option<Heartbeat>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            length_prefix: LengthPrefix::default(),
+            header_format: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            handler: Arc::new(Approve::new(Duration::ZERO)),
+            response_queue_size: RESPONSE_QUEUE_SIZE,
+            require_sign_on: false,
+            heartbeat: None,
+        }
+    }
+
+    pub fn length_prefix(mut self, length_prefix: LengthPrefix) -> Self {
+        self.length_prefix = length_prefix;
+        self
+    }
+
+    /// Expect every message to carry a header of this format between the length prefix and
+    /// the MTI.
+    pub fn header_format(mut self, header_format: HeaderFormat) -> Self {
+        self.header_format = Some(header_format);
+        self
+    }
+
+    /// Drops connections that announce a frame longer than this. Defaults to
+    /// `MAX_MESSAGE_SIZE`.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Answers requests with `handler`, usually a `Router`. Defaults to approving everything.
+    pub fn handler(mut self, handler: impl MessageHandler + 'static) -> Self {
+        self.handler = Arc::new(handler);
+        self
+    }
+
+    /// Responses a connection may have waiting to be written before it stops reading requests.
+    pub fn response_queue_size(mut self, response_queue_size: usize) -> Self {
+        self.response_queue_size = response_queue_size;
+        self
+    }
+
+    /// Rejects authorization, financial and reversal requests with response code 91 until the
+    /// peer signs on with an 0800.
+    pub fn require_sign_on(mut self, require_sign_on: bool) -> Self {
+        self.require_sign_on = require_sign_on;
+        self
+    }
+
+    /// Sends an 0800 echo test on connections that have been quiet for `heartbeat.idle`, and
+    /// closes them once `heartbeat.max_missed` echoes in a row go unanswered.
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> Result<Server, io::Error> {
+        Ok(Server {
+            listener: TcpListener::bind(addr).await?,
+            codec: IsoFrameCodec::new(self.length_prefix, self.header_format)
+                .max_message_size(self.max_message_size),
+            handler: self.handler,
+            response_queue_size: self.response_queue_size,
+            require_sign_on: self.require_sign_on,
+            heartbeat: self.heartbeat,
+        })
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Accepts ISO 8583 connections and answers every request with its `MessageHandler`.
+pub struct Server {
+    listener: TcpListener,
+    codec: IsoFrameCodec,
+    handler: Arc<dyn MessageHandler>,
+    response_queue_size: usize,
+    require_sign_on: bool,
+    heartbeat: Option<Heartbeat>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Binds a server with the default settings. Bind to port 0 and read `local_addr` to get
+    /// a free port.
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Server, io::Error> {
+        Self::builder().bind(addr).await
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the listener fails, handling each one on its own task.
+    ///
+    /// Each connection is handled in a `connection` span with the peer address and an ID
+    /// counting up from 1, and each message in a `message` span inside it.
+    pub async fn run(self) -> Result<(), io::Error> {
+        let mut connections: u32 = 0;
+
+        loop {
+            let (stream, connection_addr) = self.listener.accept().await?;
+            connections += 1;
+            let span = info_span!("connection", id = connections, peer = %connection_addr);
+            span.in_scope(|| info!("Connection made on {}", connection_addr));
+
+            let codec = self.codec.clone();
+            let handler = self.handler.clone();
+            let response_queue_size = self.response_queue_size;
+            let session = Session::new(self.require_sign_on, self.heartbeat);
+            tokio::spawn(
+                async move {
+                    match handle_connection(stream, codec, handler, response_queue_size, session)
+                        .await
+                    {
+                        Ok(_) => {
+                            info!("Successfully handled connection on {}", connection_addr)
+                        }
+                        Err(e) => {
+                            warn!(
+                            "An {} error occurred handling connection on {}. Dropping connection",
+                            e, connection_addr
+                        );
+                        }
+                    };
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+type FrameReader = FramedRead<ReadHalf<TcpStream>, IsoFrameCodec>;
+type Handlers = JoinSet<()>;
+
+async fn handle_connection(
+    stream: TcpStream,
+    codec: IsoFrameCodec,
+    handler: Arc<dyn MessageHandler>,
+    response_queue_size: usize,
+    mut session: Session,
+) -> Result<(), io::Error> {
+    let (reader, writer) = tokio::io::split(stream);
+    let (responses, mut writer_task) =
+        connection_writer::spawn(FrameWriter::new(writer, codec.clone()), response_queue_size);
+    let mut reader = FramedRead::new(reader, codec);
+    // Dropping this when the connection fails aborts every handler still running
+    let mut handlers = Handlers::new();
+
+    loop {
+        let heartbeat_due = session.heartbeat_due();
+
+        tokio::select! {
+            written = &mut writer_task => return writer_result(written),
+            Some(joined) = handlers.join_next() => handler_finished(joined),
+            _ = sleep_until_due(heartbeat_due) => match session.heartbeat() {
+                HeartbeatAction::Send(echo) => {
+                    debug!("Sending echo test");
+                    responses
+                        .send(IsoFrame::from(echo))
+                        .await
+                        .map_err(|_| writer_closed())?;
+                }
+                HeartbeatAction::Close => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "peer stopped answering echo tests",
+                    ));
+                }
+            },
+            frame = reader.next() => {
+                let frame = match frame {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                let recovering = frame.is_err();
+
+                handle_frame(frame, &handler, &mut session, &responses, &mut handlers).await?;
+
+                if recovering {
+                    // `FramedRead` yields `None` once after a decode error and then goes back
+                    // to the socket before looking at what it already buffered, so drain that
+                    // here instead of waiting on the peer.
+                    let _ = reader.next().await;
+
+                    for frame in decode_buffered(&mut reader) {
+                        handle_frame(frame, &handler, &mut session, &responses, &mut handlers)
+                            .await?;
+                    }
+                }
+            }
+        }
+    }
+
+    debug!("Connection closed");
+
+    // Let the handlers answer what was already received before closing the write half
+    while !handlers.is_empty() {
+        tokio::select! {
+            written = &mut writer_task => return writer_result(written),
+            Some(joined) = handlers.join_next() => handler_finished(joined),
+        }
+    }
+    drop(responses);
+
+    writer_result(writer_task.await)
+}
+
+/// Logs a handler that panicked or was cancelled, whose request then goes unanswered.
+fn handler_finished(joined: Result<(), JoinError>) {
+    if let Err(e) = joined {
+        warn!("Handler failed, request left unanswered: {}", e);
+    }
+}
+
+async fn sleep_until_due(due: Option<Instant>) {
+    match due {
+        Some(due) => sleep_until(due).await,
+        None => future::pending().await,
+    }
+}
+
+fn decode_buffered(reader: &mut FrameReader) -> Vec<Result<IsoFrame, FramingError>> {
+    let mut buffered = std::mem::take(reader.read_buffer_mut());
+    let mut frames = Vec::new();
+
+    loop {
+        match reader.decoder_mut().decode(&mut buffered) {
+            Ok(Some(frame)) => frames.push(Ok(frame)),
+            Ok(None) => break,
+            Err(error) => frames.push(Err(error)),
+        }
+    }
+
+    *reader.read_buffer_mut() = buffered;
+
+    frames
+}
+
+async fn handle_frame(
+    frame: Result<IsoFrame, FramingError>,
+    handler: &Arc<dyn MessageHandler>,
+    session: &mut Session,
+    responses: &Sender<IsoFrame>,
+    handlers: &mut Handlers,
+) -> Result<(), io::Error> {
+    match frame {
+        Ok(frame) => {
+            let received = Instant::now();
+            let span = message_span(&frame.message);
+            span.in_scope(|| debug!("Received message\n{}", pretty(&frame.message)));
+
+            if session.received(&frame.message) {
+                span.in_scope(|| debug!("Echo test answered"));
+                return Ok(());
+            }
+            if let Some(response_message) = session.answer(&frame.message) {
+                span.in_scope(|| responded(&response_message, received));
+                let response = IsoFrame {
+                    header: frame.header.as_ref().map(MessageHeader::to_response),
+                    message: response_message,
+                };
+                return responses.send(response).await.map_err(|_| writer_closed());
+            }
+
+            // Holding the reader here until the queue has room keeps a slow peer from
+            // piling up handlers
+            let permit = responses
+                .clone()
+                .reserve_owned()
+                .await
+                .map_err(|_| writer_closed())?;
+            handlers
+                .spawn(handle_message(frame, handler.clone(), permit, received).instrument(span));
+        }
+        Err(error @ FramingError::ShortPrefix { .. })
+        | Err(error @ FramingError::Desync { .. }) => {
+            warn!("Skipping frame: {}", error);
+        }
+        Err(FramingError::Parse {
+            header,
+            raw,
+            reason,
+        }) => {
+            warn!("Rejecting unparsable message: {}", reason);
+            let reject = format_error_response(&raw)
+                .and_then(|response_message| IsoMessage::from_buffer(response_message).ok());
+
+            if let Some(response_message) = reject {
+                let response = IsoFrame {
+                    header: header.as_ref().map(MessageHeader::to_response),
+                    message: response_message,
+                };
+                responses
+                    .send(response)
+                    .await
+                    .map_err(|_| writer_closed())?;
+            }
+        }
+        Err(FramingError::Io(e)) => return Err(e),
+        Err(error @ FramingError::InvalidPrefix { .. })
+        | Err(error @ FramingError::Oversize { .. })
+        | Err(error @ FramingError::Encode { .. }) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+    }
+
+    Ok(())
+}
+
+fn writer_result(written: Result<Result<(), FramingError>, JoinError>) -> Result<(), io::Error> {
+    match written {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(FramingError::Io(e))) => Err(e),
+        Ok(Err(error)) => Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn writer_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "connection writer stopped")
+}
+
+/// A span for handling `message`. The response code and latency are recorded once there is
+/// a response.
+fn message_span(message: &IsoMessage) -> Span {
+    info_span!(
+        "message",
+        mti = message.get_field(0),
+        stan = message.get_field(11),
+        rrn = message.get_field(37),
+        response_code = field::Empty,
+        latency_ms = field::Empty,
+    )
+}
+
+fn responded(response: &IsoMessage, received: Instant) {
+    let span = Span::current();
+    span.record("response_code", response.get_field(39));
+    span.record("latency_ms", received.elapsed().as_millis() as u64);
+    info!("Responded with {}", response.get_field(0).unwrap_or("????"));
+}
+
+async fn handle_message(
+    frame: IsoFrame,
+    handler: Arc<dyn MessageHandler>,
+    permit: OwnedPermit<IsoFrame>,
+    received: Instant,
+) {
+    match &frame.header {
+        Some(header) => debug!(
+            "Handling message from {:02x?} to {:02x?}",
+            header.source(),
+            header.destination()
+        ),
+        None => debug!("Handling message"),
+    }
+
+    if let Some(response_message) = handler.handle(&frame.message).await {
+        debug!("Responding with message\n{}", pretty(&response_message));
+        responded(&response_message, received);
+        permit.send(IsoFrame {
+            header: frame.header.as_ref().map(MessageHeader::to_response),
+            message: response_message,
+        });
+    }
+}
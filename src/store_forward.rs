@@ -0,0 +1,331 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use iso_8583_message::IsoMessage;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::{
+    correlator::{Correlator, SendError},
+    fields::{from_fields, to_fields, Fields},
+    reversal::RetryPolicy,
+};
+
+/// One line of the log, e.g. `{"queued":{"id":0,"fields":{"0":"0220",...}}}`.
+// Externally tagged since serde cannot read the numeric field keys back through the
+// buffering an internally tagged enum does
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Record {
+    Queued { id: u64, fields: Fields },
+    Delivered { id: u64 },
+}
+
+#[derive(Debug)]
+struct Advice {
+    id: u64,
+    message: IsoMessage,
+    /// Times sent without a response. Advices read back from the log count as sent once,
+    /// since there is no telling whether they went out before the restart.
+    attempts: u32,
+}
+
+#[derive(Debug)]
+struct Queue {
+    log: File,
+    advices: VecDeque<Advice>,
+    next_id: u64,
+}
+
+/// Advices (0120, 0220 and 0420) waiting to be delivered, kept in an append-only log so they
+/// survive a restart.
+///
+/// `forward` sends them in order, switching each to its repeat MTI (0121, 0221 or 0421) when
+/// it is sent again, and only moves on once the host has answered.
+#[derive(Debug)]
+pub struct StoreAndForward {
+    path: PathBuf,
+    queue: Mutex<Queue>,
+}
+
+impl StoreAndForward {
+    /// Opens the log at `path`, creating it if needed, and queues every advice in it that was
+    /// not delivered. The log is rewritten with just those advices.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut queued = BTreeMap::new();
+
+        if path.exists() {
+            for (number, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                let line = line?;
+
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_str(&line) {
+                    Ok(Record::Queued { id, fields }) => {
+                        queued.insert(id, fields);
+                    }
+                    Ok(Record::Delivered { id }) => {
+                        queued.remove(&id);
+                    }
+                    // Most likely the last line, cut short by a crash while it was written
+                    Err(e) => warn!("Skipping line {} of {}: {}", number + 1, path.display(), e),
+                }
+            }
+        }
+
+        let next_id = queued.keys().next_back().map_or(0, |id| id + 1);
+        let mut advices = VecDeque::new();
+        let mut compacted = Vec::new();
+
+        for (id, fields) in queued {
+            let message = from_fields(&fields).map_err(|reason| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("advice {} in {}: {}", id, path.display(), reason),
+                )
+            })?;
+            write_record(&mut compacted, &Record::Queued { id, fields })?;
+            advices.push_back(Advice {
+                id,
+                message,
+                attempts: 1,
+            });
+        }
+
+        // Written aside and renamed over the log so a crash leaves one or the other
+        let compacted_path = path.with_extension("compacting");
+        let mut compacted_file = File::create(&compacted_path)?;
+        compacted_file.write_all(&compacted)?;
+        compacted_file.sync_all()?;
+        std::fs::rename(&compacted_path, &path)?;
+
+        let log = OpenOptions::new().append(true).open(&path)?;
+        info!(
+            "Store and forward log {} has {} advice(s) to deliver",
+            path.display(),
+            advices.len()
+        );
+
+        Ok(Self {
+            path,
+            queue: Mutex::new(Queue {
+                log,
+                advices,
+                next_id,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Advices not delivered yet.
+    pub fn depth(&self) -> usize {
+        self.queue.lock().expect("queue poisoned").advices.len()
+    }
+
+    /// Writes `advice` to the log and queues it. Only 0120, 0220 and 0420 are accepted.
+    pub fn enqueue(&self, advice: IsoMessage) -> Result<(), io::Error> {
+        if repeat_mti(advice.get_field(0).unwrap_or_default()).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not an advice", advice.get_field(0)),
+            ));
+        }
+
+        let mut queue = self.queue.lock().expect("queue poisoned");
+        let id = queue.next_id;
+        append(
+            &mut queue.log,
+            &Record::Queued {
+                id,
+                fields: to_fields(&advice),
+            },
+        )?;
+        queue.next_id += 1;
+        queue.advices.push_back(Advice {
+            id,
+            message: advice,
+            attempts: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Sends every queued advice over `correlator` until the queue is empty, waiting between
+    /// unanswered attempts as `policy` says, never longer than `policy.max_interval_ms`.
+    /// Advices are never given up on, so `policy.max_attempts` does not apply.
+    ///
+    /// Returns early if the connection closes; what is left stays queued.
+    pub async fn forward(
+        &self,
+        correlator: &Correlator,
+        policy: &RetryPolicy,
+    ) -> Result<(), SendError> {
+        while let Some((id, advice, attempts)) = self.front() {
+            if attempts > 0 {
+                sleep(policy.wait_before(attempts.saturating_add(1))).await;
+            }
+
+            match correlator.send(advice).await {
+                Ok(response) => {
+                    debug!("Advice {} answered with {:?}", id, response.get_field(39));
+                    self.delivered(id)
+                        .map_err(|e| SendError::Invalid(e.to_string()))?;
+                }
+                Err(SendError::Timeout) => {
+                    warn!("Advice {} unanswered, will repeat it", id);
+                    self.attempted(id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The next advice to send, as an advice repeat if it was sent before.
+    fn front(&self) -> Option<(u64, IsoMessage, u32)> {
+        let queue = self.queue.lock().expect("queue poisoned");
+        let advice = queue.advices.front()?;
+        let mut message = advice.message.clone();
+
+        if advice.attempts > 0 {
+            if let Some(repeat) = message.get_field(0).and_then(repeat_mti) {
+                if let Err(e) = message.set_field(0, repeat) {
+                    warn!("Unable to set repeat MTI: {}", e);
+                }
+            }
+        }
+
+        Some((advice.id, message, advice.attempts))
+    }
+
+    fn attempted(&self, id: u64) {
+        let mut queue = self.queue.lock().expect("queue poisoned");
+
+        if let Some(advice) = queue.advices.iter_mut().find(|advice| advice.id == id) {
+            advice.attempts = advice.attempts.saturating_add(1);
+        }
+    }
+
+    fn delivered(&self, id: u64) -> Result<(), io::Error> {
+        let mut queue = self.queue.lock().expect("queue poisoned");
+        append(&mut queue.log, &Record::Delivered { id })?;
+        queue.advices.retain(|advice| advice.id != id);
+
+        Ok(())
+    }
+}
+
+/// The repeat MTI of an advice, or `None` for anything else.
+fn repeat_mti(mti: &str) -> Option<&'static str> {
+    match mti {
+        "0120" | "0121" => Some("0121"),
+        "0220" | "0221" => Some("0221"),
+        "0420" | "0421" => Some("0421"),
+        _ => None,
+    }
+}
+
+fn write_record(writer: &mut impl Write, record: &Record) -> Result<(), io::Error> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")
+}
+
+fn append(log: &mut File, record: &Record) -> Result<(), io::Error> {
+    write_record(log, record)?;
+    log.sync_data()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::PathBuf, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+
+    use super::StoreAndForward;
+    use crate::{
+        correlator::Correlator,
+        message_machine::{IsoFrame, IsoFrameCodec},
+        reversal::RetryPolicy,
+        test_support::get_message_from_file,
+    };
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("socketron-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn should_keep_advices_across_reopen() {
+        let path = log_path("reopen");
+        let store = StoreAndForward::open(&path).unwrap();
+        let advice = get_message_from_file("sample_messages/i2c-financial-advice-request.bin");
+
+        store.enqueue(advice.clone()).unwrap();
+        store.enqueue(advice).unwrap();
+        assert!(store
+            .enqueue(get_message_from_file(
+                "sample_messages/i2c-authorization-request.bin"
+            ))
+            .is_err());
+        drop(store);
+
+        let reopened = StoreAndForward::open(&path).unwrap();
+        assert_eq!(reopened.depth(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_repeat_advice_until_answered() {
+        let path = log_path("forward");
+        let store = StoreAndForward::open(&path).unwrap();
+        store
+            .enqueue(get_message_from_file(
+                "sample_messages/i2c-financial-advice-request.bin",
+            ))
+            .unwrap();
+        let (ours, theirs) = duplex(64 * 1024);
+        let correlator =
+            Correlator::new(ours, IsoFrameCodec::default()).timeout(Duration::from_millis(20));
+        let mut host = Framed::new(theirs, IsoFrameCodec::default());
+        let policy = RetryPolicy {
+            retry_interval_ms: 1,
+            ..RetryPolicy::default()
+        };
+
+        // Leaves the first copy unanswered
+        let host = tokio::spawn(async move {
+            let first = host.next().await.unwrap().unwrap().message;
+            let repeat = host.next().await.unwrap().unwrap().message;
+            host.send(IsoFrame::from(repeat.to_response("00").unwrap()))
+                .await
+                .unwrap();
+
+            [first, repeat].map(|advice| advice.get_field(0).unwrap().to_string())
+        });
+
+        store.forward(&correlator, &policy).await.unwrap();
+
+        assert_eq!(host.await.unwrap(), ["0220", "0221"]);
+        assert_eq!(store.depth(), 0);
+        drop(store);
+        assert_eq!(StoreAndForward::open(&path).unwrap().depth(), 0);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,23 @@
+use std::fs;
+
+use bytes::BytesMut;
+use iso_8583_message::IsoMessage;
+use tokio_util::codec::Decoder;
+
+use crate::message_machine::IsoFrameCodec;
+
+/// The bytes of a file in `sample_messages`, length prefix and all.
+pub(crate) fn get_buffer_from_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap()
+}
+
+/// The message in a file in `sample_messages`, framed as the default codec frames it.
+pub(crate) fn get_message_from_file(path: &str) -> IsoMessage {
+    let mut buffer = BytesMut::from(&get_buffer_from_file(path)[..]);
+
+    IsoFrameCodec::default()
+        .decode(&mut buffer)
+        .unwrap()
+        .unwrap()
+        .message
+}
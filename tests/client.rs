@@ -0,0 +1,21 @@
+use socketron::{client::read_message, correlator::Correlator, IsoFrameCodec, Server};
+
+#[tokio::test]
+async fn should_receive_response_from_server() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+    let request = read_message(
+        "sample_messages/i2c-financial-request.bin",
+        &IsoFrameCodec::default(),
+    )
+    .unwrap();
+
+    let correlator = Correlator::connect(addr, IsoFrameCodec::default())
+        .await
+        .unwrap();
+    let response = correlator.send(request).await.unwrap();
+
+    assert_eq!(response.get_field(0), Some("0210"));
+    assert_eq!(response.get_field(39), Some("00"));
+}
@@ -0,0 +1,77 @@
+use std::{env, fs, sync::Arc};
+
+use socketron::{
+    capture::{read_capture, CaptureWriter, Direction},
+    client::read_message,
+    correlator::Correlator,
+    proxy::Proxy,
+    IsoFrameCodec, Server,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn should_relay_and_record_both_directions() {
+    let capture_path = env::temp_dir().join(format!("socketron-proxy-{}.cap", std::process::id()));
+    let upstream = Server::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap().to_string();
+    tokio::spawn(upstream.run());
+    let capture = Arc::new(CaptureWriter::create(&capture_path).await.unwrap());
+    let proxy = Proxy::bind("127.0.0.1:0", &upstream_addr, IsoFrameCodec::default())
+        .await
+        .unwrap()
+        .capture(capture.clone());
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+    let request = read_message(
+        "sample_messages/i2c-authorization-request.bin",
+        &IsoFrameCodec::default(),
+    )
+    .unwrap();
+
+    let correlator = Correlator::connect(proxy_addr, IsoFrameCodec::default())
+        .await
+        .unwrap();
+    let response = correlator.send(request).await.unwrap();
+    capture.flush().await.unwrap();
+    let records = read_capture(&capture_path).unwrap();
+    fs::remove_file(&capture_path).unwrap();
+
+    assert_eq!(response.get_field(0), Some("0110"));
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].direction, Direction::FromClient);
+    assert_eq!(records[1].direction, Direction::FromUpstream);
+    assert_eq!(records[0].connection, records[1].connection);
+    assert_eq!(&records[1].frame[2..6], b"0110");
+}
+
+#[tokio::test]
+async fn should_relay_frames_that_do_not_parse_unchanged() {
+    let capture_path =
+        env::temp_dir().join(format!("socketron-proxy-raw-{}.cap", std::process::id()));
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap().to_string();
+    let capture = Arc::new(CaptureWriter::create(&capture_path).await.unwrap());
+    let proxy = Proxy::bind("127.0.0.1:0", &upstream_addr, IsoFrameCodec::default())
+        .await
+        .unwrap()
+        .capture(capture.clone());
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+    let frame = b"\x00\x05hello";
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client.write_all(frame).await.unwrap();
+    let (mut host, _) = upstream.accept().await.unwrap();
+    let mut relayed = [0; 7];
+    host.read_exact(&mut relayed).await.unwrap();
+    capture.flush().await.unwrap();
+    let records = read_capture(&capture_path).unwrap();
+    fs::remove_file(&capture_path).unwrap();
+
+    assert_eq!(&relayed, frame);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].frame, frame);
+}
@@ -0,0 +1,76 @@
+use std::{env, fs, sync::Arc, time::Duration};
+
+use socketron::{
+    capture::{read_capture, CaptureWriter},
+    client::read_message,
+    correlator::Correlator,
+    proxy::Proxy,
+    replay::Replay,
+    Approve, IsoFrameCodec, Server,
+};
+
+#[tokio::test]
+async fn should_report_responses_that_differ_from_capture() {
+    let capture_path = env::temp_dir().join(format!("socketron-replay-{}.cap", std::process::id()));
+    let recorded = Server::bind("127.0.0.1:0").await.unwrap();
+    let recorded_addr = recorded.local_addr().unwrap().to_string();
+    tokio::spawn(recorded.run());
+    let declining = Server::builder()
+        .handler(Approve::new(Duration::ZERO).response_code("05"))
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let declining_addr = declining.local_addr().unwrap().to_string();
+    tokio::spawn(declining.run());
+    let capture = Arc::new(CaptureWriter::create(&capture_path).await.unwrap());
+    let proxy = Proxy::bind("127.0.0.1:0", &recorded_addr, IsoFrameCodec::default())
+        .await
+        .unwrap()
+        .capture(capture.clone());
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+    let correlator = Correlator::connect(proxy_addr, IsoFrameCodec::default())
+        .await
+        .unwrap();
+    for path in [
+        "sample_messages/i2c-authorization-request.bin",
+        "sample_messages/i2c-financial-request.bin",
+    ] {
+        correlator
+            .send(read_message(path, &IsoFrameCodec::default()).unwrap())
+            .await
+            .unwrap();
+    }
+    capture.flush().await.unwrap();
+    let records = read_capture(&capture_path).unwrap();
+    fs::remove_file(&capture_path).unwrap();
+
+    let same = Replay::new(&recorded_addr, IsoFrameCodec::default())
+        .speed(10.0)
+        .run(&records)
+        .await
+        .unwrap();
+    let declined = Replay::new(&declining_addr, IsoFrameCodec::default())
+        .speed(10.0)
+        .run(&records)
+        .await
+        .unwrap();
+
+    assert_eq!(same.len(), 2);
+    assert!(same.iter().all(|replayed| replayed.matched()));
+    assert_eq!(same[0].request.get_field(0), Some("0100"));
+    assert_eq!(declined.len(), 2);
+    assert!(declined.iter().all(|replayed| replayed.differences == [39]));
+}
+
+#[tokio::test]
+async fn should_reject_speeds_that_are_not_positive() {
+    for speed in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        let result = Replay::new("127.0.0.1:1", IsoFrameCodec::default())
+            .speed(speed)
+            .run(&[])
+            .await;
+
+        assert!(result.is_err(), "speed {} was accepted", speed);
+    }
+}
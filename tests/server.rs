@@ -0,0 +1,197 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use iso_8583_message::IsoMessage;
+use socketron::{
+    network::Heartbeat, reversal::reversal_for, Approve, Config, IsoFrame, IsoFrameCodec,
+    MessageHandler, Router, Server,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+fn get_buffer_from_file(path: &str) -> Vec<u8> {
+    let f = File::open(path).unwrap();
+    let mut reader = BufReader::new(f);
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).unwrap();
+
+    buffer
+}
+
+struct Decline;
+
+#[async_trait]
+impl MessageHandler for Decline {
+    async fn handle(&self, request: &IsoMessage) -> Option<IsoMessage> {
+        request.to_response("05").ok()
+    }
+}
+
+struct Panic;
+
+// `async_trait` boxes the body, which clippy reads as a diverging sub-expression
+#[allow(clippy::diverging_sub_expression)]
+#[async_trait]
+impl MessageHandler for Panic {
+    async fn handle(&self, _request: &IsoMessage) -> Option<IsoMessage> {
+        panic!("handler failed");
+    }
+}
+
+async fn start(server: Server) -> SocketAddr {
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    addr
+}
+
+/// Sends `request` and reads back the first response.
+async fn exchange(addr: SocketAddr, request: &[u8]) -> IsoMessage {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request).await.unwrap();
+
+    let mut codec = IsoFrameCodec::default();
+    let mut buffer = BytesMut::new();
+
+    loop {
+        if let Some(frame) = codec.decode(&mut buffer).unwrap() {
+            return frame.message;
+        }
+        assert_ne!(stream.read_buf(&mut buffer).await.unwrap(), 0);
+    }
+}
+
+#[tokio::test]
+async fn should_report_bound_port() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+
+    assert_ne!(server.local_addr().unwrap().port(), 0);
+}
+
+#[tokio::test]
+async fn should_approve_requests_by_default() {
+    let addr = start(Server::bind("127.0.0.1:0").await.unwrap()).await;
+    let request = get_buffer_from_file("sample_messages/i2c-authorization-request.bin");
+
+    let response = exchange(addr, &request).await;
+
+    assert_eq!(response.get_field(0), Some("0110"));
+    assert_eq!(response.get_field(39), Some("00"));
+}
+
+#[tokio::test]
+async fn should_answer_with_configured_handler() {
+    let server = Server::builder()
+        .handler(Router::new(Approve::new(Duration::ZERO)).route("0200", Decline))
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = start(server).await;
+    let request = get_buffer_from_file("sample_messages/i2c-financial-request.bin");
+
+    let response = exchange(addr, &request).await;
+
+    assert_eq!(response.get_field(0), Some("0210"));
+    assert_eq!(response.get_field(39), Some("05"));
+}
+
+#[tokio::test]
+async fn should_reject_financial_requests_until_signed_on() {
+    let server = Server::builder()
+        .require_sign_on(true)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = start(server).await;
+    let request = get_buffer_from_file("sample_messages/i2c-authorization-request.bin");
+
+    let rejected = exchange(addr, &request).await;
+
+    assert_eq!(rejected.get_field(0), Some("0110"));
+    assert_eq!(rejected.get_field(39), Some("91"));
+}
+
+#[tokio::test]
+async fn should_close_connection_after_missed_echoes() {
+    let heartbeat = Heartbeat {
+        idle: Duration::from_millis(20),
+        max_missed: 2,
+    };
+    let server = Server::builder()
+        .heartbeat(heartbeat)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = start(server).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    let mut codec = IsoFrameCodec::default();
+    let mut buffer = BytesMut::new();
+    let mut echoes = Vec::new();
+
+    while stream.read_buf(&mut buffer).await.unwrap() != 0 {
+        while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+            echoes.push(frame.message);
+        }
+    }
+
+    assert_eq!(echoes.len(), 2);
+    assert!(echoes
+        .iter()
+        .all(|echo| echo.get_field(0) == Some("0800") && echo.get_field(70) == Some("301")));
+    assert_ne!(echoes[0].get_field(11), echoes[1].get_field(11));
+}
+
+#[tokio::test]
+async fn should_share_journal_between_listeners() {
+    let builder = Config::default().server_builder().unwrap();
+    let first = start(builder.clone().bind("127.0.0.1:0").await.unwrap()).await;
+    let second = start(builder.bind("127.0.0.1:0").await.unwrap()).await;
+    let request = get_buffer_from_file("sample_messages/i2c-authorization-request.bin");
+    let original = IsoFrameCodec::default()
+        .decode(&mut BytesMut::from(&request[..]))
+        .unwrap()
+        .unwrap()
+        .message;
+    let mut reversal = BytesMut::new();
+    IsoFrameCodec::default()
+        .encode(
+            IsoFrame::from(reversal_for(&original).unwrap()),
+            &mut reversal,
+        )
+        .unwrap();
+
+    let response = exchange(first, &request).await;
+    let reversal_response = exchange(second, &reversal).await;
+
+    assert_eq!(response.get_field(39), Some("00"));
+    assert_eq!(reversal_response.get_field(0), Some("0430"));
+    assert_eq!(reversal_response.get_field(39), Some("00"));
+}
+
+#[tokio::test]
+async fn should_keep_answering_after_a_handler_panics() {
+    let server = Server::builder()
+        .handler(Router::new(Approve::new(Duration::ZERO)).route("0200", Panic))
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = start(server).await;
+    let mut request = get_buffer_from_file("sample_messages/i2c-financial-request.bin");
+    request.extend(get_buffer_from_file(
+        "sample_messages/i2c-authorization-request.bin",
+    ));
+
+    let response = exchange(addr, &request).await;
+
+    assert_eq!(response.get_field(0), Some("0110"));
+}